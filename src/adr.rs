@@ -0,0 +1,239 @@
+use crate::regions::{self, DataRate};
+use crate::{
+    ConfigOption, DriverError, LoraRegion, Port, QoS, Rak811Driver, Response, UplinkResult,
+};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::{serial::Read, serial::Write};
+use heapless::ArrayLength;
+
+/// Margin policy of a `RateController`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateConfig {
+    /// Most robust data rate, used after missed acknowledgements.
+    pub min_dr: u8,
+    /// Fastest data rate to step up to.
+    pub max_dr: u8,
+    /// SNR margin in dB above the demodulation floor needed to step up.
+    pub step_up_margin: i8,
+    /// SNR margin in dB below which the data rate is stepped down.
+    pub step_down_margin: i8,
+    /// Number of consecutive missed acknowledgements before falling back to `min_dr`.
+    pub max_missed_acks: u8,
+}
+
+impl Default for RateConfig {
+    fn default() -> Self {
+        RateConfig {
+            min_dr: 0,
+            max_dr: 5,
+            step_up_margin: 15,
+            step_down_margin: 10,
+            max_missed_acks: 3,
+        }
+    }
+}
+
+/// Host-side alternative to network controlled ADR, for devices where the network does not
+/// adapt quickly enough, such as mobile assets.
+///
+/// The data rate is adapted using confirmed uplinks: after each acknowledgement, the signal
+/// quality reported by the module is compared with the demodulation floor of the data rate,
+/// and the data rate is stepped up or down one at a time.
+pub struct RateController {
+    config: RateConfig,
+    data_rate: u8,
+    missed_acks: u8,
+}
+
+impl RateController {
+    /// Create a controller starting at the most robust data rate.
+    pub fn new(config: RateConfig) -> Self {
+        RateController {
+            config,
+            data_rate: config.min_dr,
+            missed_acks: 0,
+        }
+    }
+
+    /// Data rate currently selected.
+    pub fn data_rate(&self) -> u8 {
+        self.data_rate
+    }
+
+    /// Turn off network controlled ADR in the module and apply the current data rate.
+    pub fn start<W, R, RST, BUF, RXQ, CMD>(
+        &mut self,
+        driver: &mut Rak811Driver<W, R, RST, BUF, RXQ, CMD>,
+    ) -> Result<(), DriverError>
+    where
+        W: Write<u8>,
+        R: Read<u8>,
+        RST: OutputPin,
+        BUF: ArrayLength<u8>,
+        RXQ: ArrayLength<Response>,
+        CMD: ArrayLength<u8>,
+    {
+        driver.set_config(ConfigOption::Adr(false))?;
+        driver.set_data_rate(self.data_rate)
+    }
+
+    /// Send a confirmed uplink, and adapt the data rate to its outcome. Unless the driver reports
+    /// the signal quality in the result, the link status is read from the module after each
+    /// acknowledgement.
+    pub fn send<W, R, RST, BUF, RXQ, CMD>(
+        &mut self,
+        driver: &mut Rak811Driver<W, R, RST, BUF, RXQ, CMD>,
+        port: Port,
+        data: &[u8],
+    ) -> Result<UplinkResult, DriverError>
+    where
+        W: Write<u8>,
+        R: Read<u8>,
+        RST: OutputPin,
+        BUF: ArrayLength<u8>,
+        RXQ: ArrayLength<Response>,
+        CMD: ArrayLength<u8>,
+    {
+        let result = driver.send(QoS::Confirmed, port, data)?;
+        let next = match (result.acked, result.snr) {
+            (true, Some(snr)) => self.record_ack(driver.lora_band(), snr),
+            (true, None) => {
+                let snr = driver.get_status()?.snr;
+                self.record_ack(driver.lora_band(), snr)
+            }
+            (false, _) => self.record_missed_ack(),
+        };
+        if let Some(dr) = next {
+            driver.set_data_rate(dr)?;
+        }
+        Ok(result)
+    }
+
+    /// Record an acknowledged uplink with the SNR of the acknowledgement in dB. Returns the new
+    /// data rate if it should change.
+    pub fn record_ack(&mut self, region: LoraRegion, snr: i8) -> Option<u8> {
+        self.missed_acks = 0;
+        let margin = snr as i32 - required_snr(region, self.data_rate)?;
+        let dr = self.data_rate;
+        if margin >= self.config.step_up_margin as i32
+            && dr < self.config.max_dr
+            && regions::max_payload_len(region, dr + 1).is_some()
+        {
+            self.change(dr + 1)
+        } else if margin < self.config.step_down_margin as i32 && dr > self.config.min_dr {
+            self.change(dr - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Record an uplink which was not acknowledged. Returns the new data rate if it should change.
+    pub fn record_missed_ack(&mut self) -> Option<u8> {
+        self.missed_acks = self.missed_acks.saturating_add(1);
+        if self.missed_acks < self.config.max_missed_acks || self.data_rate == self.config.min_dr {
+            return None;
+        }
+        self.missed_acks = 0;
+        warn!("No acknowledgement received, falling back to the most robust data rate");
+        self.change(self.config.min_dr)
+    }
+
+    fn change(&mut self, dr: u8) -> Option<u8> {
+        debug!("Changing data rate from {} to {}", self.data_rate, dr);
+        self.data_rate = dr;
+        Some(dr)
+    }
+}
+
+// Lowest SNR in dB at which a LoRa packet can be demodulated at the data rate, rounded up.
+fn required_snr(region: LoraRegion, dr: u8) -> Option<i32> {
+    match regions::data_rate(region, dr)? {
+        DataRate::Lora {
+            spreading_factor, ..
+        } => Some(-(spreading_factor as i32 - 4) * 5 / 2),
+        DataRate::Fsk { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_with_margin() {
+        let mut controller = RateController::new(RateConfig::default());
+        let region = LoraRegion::EU868;
+        // SF12 needs -20 dB, SF11 -17.5 dB and SF10 -15 dB
+        assert_eq!(Some(1), controller.record_ack(region, 0));
+        assert_eq!(Some(2), controller.record_ack(region, 0));
+        assert_eq!(Some(3), controller.record_ack(region, 0));
+        // SF9 needs -12.5 dB
+        assert_eq!(None, controller.record_ack(region, 0));
+        assert_eq!(Some(4), controller.record_ack(region, 3));
+        controller.data_rate = 5;
+        assert_eq!(None, controller.record_ack(region, 20));
+        // SF7 needs -7.5 dB
+        assert_eq!(Some(4), controller.record_ack(region, 2));
+    }
+
+    #[test]
+    fn low_margin_does_not_step_up() {
+        let mut controller = RateController::new(RateConfig::default());
+        let region = LoraRegion::EU868;
+        // 8 dB above the SF12 floor is well below the step up margin
+        assert_eq!(None, controller.record_ack(region, -12));
+        assert_eq!(None, controller.record_ack(region, -20));
+        controller.data_rate = 3;
+        assert_eq!(Some(2), controller.record_ack(region, -7));
+    }
+
+    #[test]
+    fn falls_back_after_missed_acks() {
+        let mut controller = RateController::new(RateConfig::default());
+        controller.data_rate = 4;
+        assert_eq!(None, controller.record_missed_ack());
+        assert_eq!(None, controller.record_missed_ack());
+        assert_eq!(Some(0), controller.record_missed_ack());
+        assert_eq!(None, controller.record_missed_ack());
+    }
+
+    // Driver tests log through defmt, which needs a global logger not available on the host.
+    #[cfg(not(feature = "defmt"))]
+    #[test]
+    fn send_steps_down_on_low_margin() {
+        let module = crate::mock::Module::new();
+        let mut driver = module.driver().unwrap();
+        let mut controller = RateController::new(RateConfig::default());
+        controller.data_rate = 3;
+        // SF9 needs -12.5 dB, so -7 dB leaves a margin below the step down margin
+        module
+            .expect("at+get_config=dr", "OK3\r\n")
+            .expect("at+send=1,2,12", "OK\r\nat+recv=1,0,0\r\n")
+            .expect("at+status", "OK4,3,3,1,0,-112,-7\r\n")
+            .expect("at+set_config=dr:2", "OK\r\n");
+        let result = controller.send(&mut driver, 2, &[0x12]).unwrap();
+        module.done();
+        assert!(result.acked);
+        assert_eq!(2, controller.data_rate());
+    }
+
+    #[cfg(not(feature = "defmt"))]
+    #[test]
+    fn send_falls_back_after_missed_acks() {
+        let module = crate::mock::Module::new();
+        let mut driver = module.driver().unwrap();
+        let mut controller = RateController::new(RateConfig::default());
+        controller.data_rate = 4;
+        module.expect("at+get_config=dr", "OK4\r\n");
+        for _ in 0..3 {
+            module.expect("at+send=1,2,12", "OK\r\nat+recv=6,0,0\r\n");
+        }
+        module.expect("at+set_config=dr:0", "OK\r\n");
+        for _ in 0..3 {
+            let result = controller.send(&mut driver, 2, &[0x12]).unwrap();
+            assert!(!result.acked);
+        }
+        module.done();
+        assert_eq!(0, controller.data_rate());
+    }
+}
@@ -1,23 +1,68 @@
 use crate::parser;
-use crate::protocol::Response;
+use crate::pool::{PayloadHandle, PayloadPool};
+use crate::protocol::{Response, ResponseParser};
 use core::str::from_utf8;
+use heapless::{consts, ArrayLength, Vec};
 use moveslice::Moveslice;
+use nom::IResult;
+use zeroize::Zeroize;
 
-pub struct Buffer {
-    buffer: [u8; 512],
+/// Buffer for data received from the module, holding `N` bytes.
+pub struct Buffer<N: ArrayLength<u8> = consts::U512> {
+    buffer: Vec<u8, N>,
     pos: usize,
     needs_parse: bool,
+    redact: bool,
+    raw: bool,
+    multiline: bool,
+    parsers: Vec<ResponseParser, consts::U4>,
 }
 
-impl Buffer {
+impl<N: ArrayLength<u8>> Buffer<N> {
     pub fn new() -> Self {
+        // The whole capacity is used as storage, with `pos` tracking the data received.
+        let mut buffer = Vec::new();
+        buffer.resize_default(buffer.capacity()).ok();
         Buffer {
-            buffer: [0; 512],
+            buffer,
             pos: 0,
             needs_parse: false,
+            redact: false,
+            raw: false,
+            multiline: false,
+            parsers: Vec::new(),
         }
     }
 
+    /// Add a parser tried before the builtin parsers. If all slots are taken,
+    /// the parser is handed back.
+    pub fn add_parser(&mut self, parser: ResponseParser) -> Result<(), ResponseParser> {
+        self.parsers.push(parser)
+    }
+
+    /// Collect lines up to the terminating `OK` or `ERROR` into a single response,
+    /// used while awaiting the response to a multi-line command.
+    pub fn set_multiline(&mut self, multiline: bool) {
+        self.multiline = multiline;
+    }
+
+    /// Parse input line by line instead of into typed responses, used while
+    /// awaiting the response to a raw command.
+    pub fn set_raw(&mut self, raw: bool) {
+        self.raw = raw;
+    }
+
+    /// Hide buffer contents from log output and wipe parsed data, for responses
+    /// carrying key material.
+    pub fn set_redact(&mut self, redact: bool) {
+        self.redact = redact;
+    }
+
+    /// True if buffer contents must not be logged.
+    pub fn is_redacted(&self) -> bool {
+        self.redact || cfg!(feature = "no-command-log")
+    }
+
     pub fn write(&mut self, octet: u8) -> Result<(), u8> {
         if self.pos >= self.buffer.len() {
             Err(octet)
@@ -29,7 +74,9 @@ impl Buffer {
         }
     }
 
-    pub fn parse(&mut self) -> Result<Response, ()> {
+    /// Parse a response from the buffered data. Received payloads and multi-line responses
+    /// are stored in the pool.
+    pub fn parse(&mut self, pool: &mut PayloadPool<N>) -> Result<Response, ()> {
         if self.pos == 0 {
             return Ok(Response::None);
         }
@@ -40,19 +87,34 @@ impl Buffer {
 
         let str = from_utf8(&self.buffer[0..self.pos]);
         match str {
+            _ if self.is_redacted() => {
+                debug!("parsing {} [<redacted>]", self.pos);
+            }
             Ok(s) => {
-                log::debug!("parsing {} [{}]", self.pos, s);
+                debug!("parsing {} [{}]", self.pos, s);
             }
             Err(e) => {
                 let s = from_utf8(&self.buffer[0..e.valid_up_to()]).unwrap();
-                log::debug!("parsing {} [{}<truncated>] ({})", self.pos, s, e);
+                debug!(
+                    "parsing {} [{}<truncated>] (invalid UTF-8 at {})",
+                    self.pos,
+                    s,
+                    e.valid_up_to()
+                );
+            }
+        }
+
+        if self.multiline {
+            if let Some(response) = self.extract_recv(pool) {
+                return Ok(response);
             }
         }
 
         let mut ret = Ok(Response::None);
 
-        if let Ok((remainder, response)) = parser::parse(&self.buffer[0..self.pos]) {
+        if let Ok((remainder, response)) = self.parse_input(&self.buffer[0..self.pos], pool) {
             let len = remainder.len();
+            let consumed = self.pos;
             if len > 0 {
                 let start = self.pos - len;
                 (&mut self.buffer[..]).moveslice(start..start + len, 0);
@@ -61,6 +123,9 @@ impl Buffer {
             } else {
                 self.pos = 0;
             }
+            if self.redact {
+                self.buffer[self.pos..consumed].zeroize();
+            }
             ret = Ok(response);
         }
 
@@ -104,4 +169,139 @@ impl Buffer {
         //Ok(Response::None)
         ret
     }
+
+    // Take an event received in the middle of a multi-line response out of the buffer, so that
+    // it is not framed as part of the response.
+    fn extract_recv(&mut self, pool: &mut PayloadPool<N>) -> Option<Response> {
+        let input = &self.buffer[0..self.pos];
+        let start = input
+            .windows(9)
+            .position(|w| w == b"\nat+recv=")
+            .map(|i| i + 1)?;
+        let (remainder, response) = parse_recv(&input[start..], pool).ok()?;
+        let end = self.pos - remainder.len();
+        self.buffer[..].moveslice(end..self.pos, start);
+        self.pos -= end - start;
+        self.needs_parse = true;
+        Some(response)
+    }
+
+    // Run the custom parsers in order of registration, falling back to the builtin parsers
+    // if none of them recognizes the input.
+    fn parse_input<'i>(
+        &self,
+        input: &'i [u8],
+        pool: &mut PayloadPool<N>,
+    ) -> IResult<&'i [u8], Response> {
+        match parse_recv(input, pool) {
+            Err(nom::Err::Error(_)) => {}
+            result => return result,
+        }
+        if self.raw {
+            return parser::line(input);
+        }
+        if self.multiline {
+            return parse_lines(input, pool);
+        }
+        for parser in self.parsers.iter() {
+            match parser(input) {
+                Err(nom::Err::Error(_)) => {}
+                result => return result,
+            }
+        }
+        parser::parse(input)
+    }
+}
+
+// Parse an event and copy its payload to the pool.
+fn parse_recv<'i, N: ArrayLength<u8>>(
+    input: &'i [u8],
+    pool: &mut PayloadPool<N>,
+) -> IResult<&'i [u8], Response> {
+    let (remainder, (code, port, data)) = parser::recv(input)?;
+    let handle = if data.is_empty() {
+        None
+    } else {
+        store(pool, data.len(), |out| out.copy_from_slice(data))
+    };
+    Ok((remainder, Response::Recv(code, port, data.len(), handle)))
+}
+
+// Frame a multi-line response and copy its content to the pool. The input is framed
+// first, so that nothing is stored for incomplete responses.
+fn parse_lines<'i, N: ArrayLength<u8>>(
+    input: &'i [u8],
+    pool: &mut PayloadPool<N>,
+) -> IResult<&'i [u8], Response> {
+    let mut len = 0;
+    let (remainder, result) = parser::lines(input, &mut |b| len += b.len())?;
+    if let Err(code) = result {
+        return Ok((remainder, Response::Error(code)));
+    }
+    let handle = store(pool, len, |out| {
+        let mut pos = 0;
+        parser::lines(input, &mut |b| {
+            out[pos..pos + b.len()].copy_from_slice(b);
+            pos += b.len();
+        })
+        .ok();
+    });
+    match handle {
+        Some(handle) => Ok((remainder, Response::Lines(handle))),
+        None => Err(nom::Err::Failure((input, nom::error::ErrorKind::TooLarge))),
+    }
+}
+
+fn store<N: ArrayLength<u8>, F: FnOnce(&mut [u8])>(
+    pool: &mut PayloadPool<N>,
+    len: usize,
+    f: F,
+) -> Option<PayloadHandle> {
+    let handle = pool.alloc(len)?;
+    if let Some(out) = pool.get_mut(&handle) {
+        f(out);
+    }
+    Some(handle)
+}
+
+// Parsing logs through defmt, which needs a global logger not available on the host.
+#[cfg(all(test, not(feature = "defmt")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted_input_is_wiped() {
+        let mut buffer: Buffer = Buffer::new();
+        let mut pool = PayloadPool::new();
+        buffer.set_redact(true);
+        for b in b"OKabababababababababababababababab\r\nOK".iter() {
+            buffer.write(*b).unwrap();
+        }
+        assert!(matches!(buffer.parse(&mut pool), Ok(Response::Config(_))));
+        assert_eq!(b"OK", &buffer.buffer[..buffer.pos]);
+        assert!(buffer.buffer[buffer.pos..36].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn events_are_not_framed_in_multiline_responses() {
+        let mut buffer: Buffer = Buffer::new();
+        let mut pool = PayloadPool::new();
+        buffer.set_multiline(true);
+        for b in b"0,on,868100000,0,5\r\nat+recv=0,2,2ab\r\n1,on,868300000,0,5\r\nOK\r\n".iter() {
+            buffer.write(*b).unwrap();
+        }
+        match buffer.parse(&mut pool) {
+            Ok(Response::Recv(_, 2, 2, Some(handle))) => {
+                assert_eq!(Some(&b"ab"[..]), pool.get(&handle))
+            }
+            r => panic!("{:?}", r),
+        }
+        match buffer.parse(&mut pool) {
+            Ok(Response::Lines(handle)) => assert_eq!(
+                Some(&b"0,on,868100000,0,5\n1,on,868300000,0,5"[..]),
+                pool.get(&handle)
+            ),
+            r => panic!("{:?}", r),
+        }
+    }
 }
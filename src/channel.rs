@@ -0,0 +1,86 @@
+use crate::DriverError;
+use core::str::FromStr;
+use heapless::{consts, Vec};
+
+/// Channel as reported by `at+get_config=ch_list`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Channel {
+    pub index: u8,
+    pub enabled: bool,
+    /// Frequency in Hz.
+    pub frequency: u32,
+    pub min_dr: u8,
+    pub max_dr: u8,
+}
+
+/// Channels of a region, up to the 72 channels used in US915 and AU915.
+pub type ChannelList = Vec<Channel, consts::U72>;
+
+impl FromStr for Channel {
+    type Err = DriverError;
+
+    /// Parse a single entry such as `*0,on,868100000,0,5`. The leading `*` is optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.trim().trim_start_matches('*').split(',');
+        let mut next = || fields.next().ok_or(DriverError::UnexpectedResponse);
+        let index = next()?;
+        let enabled = next()?;
+        let frequency = next()?;
+        let min_dr = next()?;
+        let max_dr = next()?;
+        Ok(Channel {
+            index: parse_field(index)?,
+            enabled: match enabled {
+                "on" => true,
+                "off" => false,
+                _ => return Err(DriverError::UnexpectedResponse),
+            },
+            frequency: parse_field(frequency)?,
+            min_dr: parse_field(min_dr)?,
+            max_dr: parse_field(max_dr)?,
+        })
+    }
+}
+
+fn parse_field<T: FromStr>(field: &str) -> Result<T, DriverError> {
+    field.parse().map_err(|_| DriverError::UnexpectedResponse)
+}
+
+/// Parse the channel list reported by the module, with entries separated by `;` or line breaks.
+pub fn parse_channel_list(content: &str) -> Result<ChannelList, DriverError> {
+    let mut channels = ChannelList::new();
+    for entry in content.split([';', '\n']) {
+        if entry.trim().is_empty() {
+            continue;
+        }
+        channels
+            .push(entry.parse()?)
+            .map_err(|_| DriverError::UnexpectedResponse)?;
+    }
+    Ok(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_channels() {
+        let channels =
+            parse_channel_list("*0,on,868100000,0,5;*1,on,868300000,0,5;\n3,off,0,0,0").unwrap();
+        assert_eq!(3, channels.len());
+        assert_eq!(
+            Channel {
+                index: 1,
+                enabled: true,
+                frequency: 868_300_000,
+                min_dr: 0,
+                max_dr: 5
+            },
+            channels[1]
+        );
+        assert!(!channels[2].enabled);
+        assert!(parse_channel_list("0,maybe,868100000,0,5").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::protocol::Redacted;
+use crate::{
+    AppKey, AppsKey, ConfigKey, ConfigOption, ConnectMode, DevAddr, LoraMode, LoraRegion, NwksKey,
+    EUI,
+};
+use core::fmt::{self, Debug, Formatter};
+use heapless::{consts, Vec};
+
+/// Desired configuration of the module, applied using `Rak811Driver::apply`. Settings
+/// left as `None` are not touched.
+#[derive(Default)]
+pub struct Rak811Config<'a> {
+    pub band: Option<LoraRegion>,
+    pub mode: Option<LoraMode>,
+    pub connect_mode: Option<ConnectMode>,
+    pub device_address: Option<&'a DevAddr>,
+    pub device_eui: Option<&'a EUI>,
+    pub app_eui: Option<&'a EUI>,
+    pub app_key: Option<&'a AppKey>,
+    pub nwks_key: Option<&'a NwksKey>,
+    pub apps_key: Option<&'a AppsKey>,
+    pub data_rate: Option<u8>,
+    pub adr: Option<bool>,
+    pub tx_power: Option<u8>,
+}
+
+impl<'a> Debug for Rak811Config<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rak811Config")
+            .field("band", &self.band)
+            .field("mode", &self.mode)
+            .field("connect_mode", &self.connect_mode)
+            .field("device_address", &self.device_address)
+            .field("device_eui", &self.device_eui)
+            .field("app_eui", &self.app_eui)
+            .field("app_key", &self.app_key.map(|_| Redacted))
+            .field("nwks_key", &self.nwks_key.map(|_| Redacted))
+            .field("apps_key", &self.apps_key.map(|_| Redacted))
+            .field("data_rate", &self.data_rate)
+            .field("adr", &self.adr)
+            .field("tx_power", &self.tx_power)
+            .finish()
+    }
+}
+
+impl<'a> Rak811Config<'a> {
+    /// The configuration options stored using `at+set_config`.
+    pub fn options(&self) -> Vec<ConfigOption<'a>, consts::U16> {
+        let mut options = Vec::new();
+        let mut push = |option: Option<ConfigOption<'a>>| {
+            if let Some(option) = option {
+                options.push(option).ok();
+            }
+        };
+        push(self.device_address.map(ConfigOption::DevAddr));
+        push(self.device_eui.map(ConfigOption::DevEui));
+        push(self.app_eui.map(ConfigOption::AppEui));
+        push(self.app_key.map(ConfigOption::AppKey));
+        push(self.nwks_key.map(ConfigOption::NwksKey));
+        push(self.apps_key.map(ConfigOption::AppsKey));
+        push(self.data_rate.map(ConfigOption::Dr));
+        push(self.adr.map(ConfigOption::Adr));
+        push(self.tx_power.map(ConfigOption::PwrLevel));
+        options
+    }
+}
+
+/// Settings changed when applying a `Rak811Config`.
+#[derive(Debug, Default)]
+pub struct ConfigDiff {
+    pub band: bool,
+    pub mode: bool,
+    pub connect_mode: bool,
+    pub options: Vec<ConfigKey, consts::U16>,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for ConfigDiff {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "ConfigDiff {{ band: {}, mode: {}, connect_mode: {}, options: {} }}",
+            self.band,
+            self.mode,
+            self.connect_mode,
+            &self.options[..]
+        )
+    }
+}
+
+impl ConfigDiff {
+    /// True if the module already had the desired configuration.
+    pub fn is_empty(&self) -> bool {
+        !self.band && !self.mode && !self.connect_mode && self.options.is_empty()
+    }
+}
+
+/// Configuration options applied as a unit using `Rak811Driver::commit`.
+#[derive(Debug, Default)]
+pub struct ConfigTransaction<'a> {
+    options: Vec<ConfigOption<'a>, consts::U8>,
+}
+
+impl<'a> ConfigTransaction<'a> {
+    pub fn new() -> Self {
+        ConfigTransaction {
+            options: Vec::new(),
+        }
+    }
+
+    /// Stage an option to be applied when committing. If the transaction is full,
+    /// the option is handed back.
+    pub fn stage(&mut self, option: ConfigOption<'a>) -> Result<(), ConfigOption<'a>> {
+        self.options.push(option)
+    }
+
+    pub fn options(&self) -> &[ConfigOption<'a>] {
+        &self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_skip_unset() {
+        let config = Rak811Config {
+            data_rate: Some(5),
+            adr: Some(false),
+            ..Default::default()
+        };
+        let options = config.options();
+        assert_eq!(2, options.len());
+        assert_eq!(ConfigKey::Dr, options[0].key());
+        assert!(options[1].matches("off"));
+    }
+}
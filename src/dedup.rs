@@ -0,0 +1,88 @@
+use crate::Port;
+use heapless::{consts, ArrayLength, Vec};
+
+/// Remembers hashes of the last payloads received on each port, to detect downlinks delivered
+/// more than once, such as retransmissions the module does not report as repeated.
+///
+/// `N` bounds the number of hashes kept over all ports. When full, the oldest hash is forgotten.
+pub struct DedupWindow<N = consts::U16>
+where
+    N: ArrayLength<(Port, u32)>,
+{
+    per_port: usize,
+    hashes: Vec<(Port, u32), N>,
+}
+
+impl<N> DedupWindow<N>
+where
+    N: ArrayLength<(Port, u32)>,
+{
+    /// Create a window remembering the last `per_port` payloads of each port.
+    pub fn new(per_port: usize) -> Self {
+        DedupWindow {
+            per_port,
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Check if the payload matches one of the last payloads received on the port, and
+    /// remember it otherwise.
+    pub fn is_duplicate(&mut self, port: Port, data: &[u8]) -> bool {
+        let hash = fnv1a(data);
+        if self.hashes.iter().any(|&(p, h)| p == port && h == hash) {
+            return true;
+        }
+        if self.per_port == 0 {
+            return false;
+        }
+        let on_port = self.hashes.iter().filter(|(p, _)| *p == port).count();
+        if on_port >= self.per_port {
+            if let Some(i) = self.hashes.iter().position(|(p, _)| *p == port) {
+                self.remove(i);
+            }
+        } else if self.hashes.len() == self.hashes.capacity() {
+            self.remove(0);
+        }
+        self.hashes.push((port, hash)).ok();
+        false
+    }
+
+    /// Forget all payloads.
+    pub fn clear(&mut self) {
+        self.hashes.clear();
+    }
+
+    // Remove the hash at the index, keeping the order of the remaining hashes.
+    fn remove(&mut self, index: usize) {
+        for i in index..self.hashes.len() - 1 {
+            self.hashes.swap(i, i + 1);
+        }
+        self.hashes.pop();
+    }
+}
+
+// 32-bit FNV-1a hash.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, b| {
+        (hash ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_duplicates_per_port() {
+        let mut window: DedupWindow<consts::U4> = DedupWindow::new(2);
+        assert!(!window.is_duplicate(1, b"on"));
+        assert!(window.is_duplicate(1, b"on"));
+        assert!(!window.is_duplicate(2, b"on"));
+        assert!(!window.is_duplicate(1, b"off"));
+
+        // Only the last two payloads of port 1 are kept
+        assert!(!window.is_duplicate(1, b"toggle"));
+        assert!(!window.is_duplicate(1, b"on"));
+        assert!(window.is_duplicate(2, b"on"));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{ConnectMode, DriverError, DriverMetrics, FirmwareInfo, LoraMode, LoraRegion};
+
+/// Link counters and signal quality of the last received packet, as reported by `at+status`.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkStatus {
+    pub tx_ok: u8,
+    pub tx_err: u8,
+    pub rx_ok: u8,
+    pub rx_timeout: u8,
+    pub rx_err: u8,
+    pub rssi: i8,
+    pub snr: i8,
+}
+
+impl LinkStatus {
+    // Transmissions counted by the module, wrapping like the counters.
+    pub(crate) fn transmissions(&self) -> u8 {
+        self.tx_ok.wrapping_add(self.tx_err)
+    }
+}
+
+/// Summary of the driver metrics included in a diagnostics report.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MetricsSummary {
+    pub commands: u32,
+    pub errors: u32,
+    pub timeouts: u32,
+    pub overflows: u32,
+    pub downlinks: u32,
+}
+
+impl From<&DriverMetrics> for MetricsSummary {
+    fn from(metrics: &DriverMetrics) -> Self {
+        MetricsSummary {
+            commands: metrics.total_commands(),
+            errors: metrics.total_errors(),
+            timeouts: metrics.timeouts,
+            overflows: metrics.overflows,
+            downlinks: metrics.downlinks,
+        }
+    }
+}
+
+/// Snapshot of the module and driver state, collected using `Rak811Driver::diagnostics`.
+/// Values which could not be read from the module are left as zero.
+#[derive(Debug)]
+pub struct Diagnostics {
+    pub firmware: FirmwareInfo,
+    pub band: LoraRegion,
+    pub mode: LoraMode,
+    pub connect_mode: ConnectMode,
+    pub joined: bool,
+    pub device_eui: [u8; 8],
+    pub app_eui: [u8; 8],
+    pub channel_mask: u16,
+    pub data_rate: u8,
+    pub status: LinkStatus,
+    pub metrics: MetricsSummary,
+}
+
+/// Length of the encoded diagnostics report.
+pub const DIAGNOSTICS_LEN: usize = 43;
+
+const DIAGNOSTICS_FORMAT: u8 = 1;
+
+impl Diagnostics {
+    /// Encode the report in a compact binary format of `DIAGNOSTICS_LEN` bytes, small enough to
+    /// be sent as an uplink. Multi-byte values are big endian, and counters saturate at their
+    /// maximum value.
+    ///
+    /// | Offset | Length | Value                                                       |
+    /// |--------|--------|-------------------------------------------------------------|
+    /// | 0      | 1      | Format version (1)                                          |
+    /// | 1      | 4      | Firmware major, minor, patch and build                      |
+    /// | 5      | 1      | Band: EU868, US915, AU915, KR920, AS923, IN865 as 0-5, 255 if unknown |
+    /// | 6      | 1      | Flags: bit 0 P2P mode, bit 1 ABP, bit 2 joined              |
+    /// | 7      | 8      | Device EUI                                                  |
+    /// | 15     | 8      | Application EUI                                             |
+    /// | 23     | 2      | Channel mask                                                |
+    /// | 25     | 1      | Data rate                                                   |
+    /// | 26     | 5      | Link status tx ok, tx error, rx ok, rx timeout, rx error    |
+    /// | 31     | 1      | RSSI (signed)                                               |
+    /// | 32     | 1      | SNR (signed)                                                |
+    /// | 33     | 10     | Commands, errors, timeouts, overflows and downlinks (u16)   |
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, DriverError> {
+        if buf.len() < DIAGNOSTICS_LEN {
+            return Err(DriverError::OtherError);
+        }
+        buf[0] = DIAGNOSTICS_FORMAT;
+        buf[1] = self.firmware.major;
+        buf[2] = self.firmware.minor;
+        buf[3] = self.firmware.patch;
+        buf[4] = self.firmware.build;
+        buf[5] = match self.band {
+            LoraRegion::EU868 => 0,
+            LoraRegion::US915 => 1,
+            LoraRegion::AU915 => 2,
+            LoraRegion::KR920 => 3,
+            LoraRegion::AS923 => 4,
+            LoraRegion::IN865 => 5,
+            LoraRegion::UNKNOWN => 255,
+        };
+        let mut flags = 0;
+        if let LoraMode::P2P = self.mode {
+            flags |= 0x01;
+        }
+        if let ConnectMode::ABP = self.connect_mode {
+            flags |= 0x02;
+        }
+        if self.joined {
+            flags |= 0x04;
+        }
+        buf[6] = flags;
+        buf[7..15].copy_from_slice(&self.device_eui);
+        buf[15..23].copy_from_slice(&self.app_eui);
+        buf[23..25].copy_from_slice(&self.channel_mask.to_be_bytes());
+        buf[25] = self.data_rate;
+        buf[26] = self.status.tx_ok;
+        buf[27] = self.status.tx_err;
+        buf[28] = self.status.rx_ok;
+        buf[29] = self.status.rx_timeout;
+        buf[30] = self.status.rx_err;
+        buf[31] = self.status.rssi as u8;
+        buf[32] = self.status.snr as u8;
+        let counters = [
+            self.metrics.commands,
+            self.metrics.errors,
+            self.metrics.timeouts,
+            self.metrics.overflows,
+            self.metrics.downlinks,
+        ];
+        for (i, counter) in counters.iter().enumerate() {
+            let value = core::cmp::min(*counter, u16::MAX as u32) as u16;
+            buf[33 + i * 2..35 + i * 2].copy_from_slice(&value.to_be_bytes());
+        }
+        Ok(DIAGNOSTICS_LEN)
+    }
+}
+
+/// Decode a hex string as reported by the module into the given buffer.
+pub(crate) fn decode_hex(value: &str, out: &mut [u8]) -> bool {
+    let value = value.as_bytes();
+    if value.len() != out.len() * 2 {
+        return false;
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        match (hex_digit(value[i * 2]), hex_digit(value[i * 2 + 1])) {
+            (Some(hi), Some(lo)) => *b = hi << 4 | lo,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_eui() {
+        let mut eui = [0; 8];
+        assert!(decode_hex("00BB7c95adb530b9", &mut eui));
+        assert_eq!([0x00, 0xBB, 0x7C, 0x95, 0xAD, 0xB5, 0x30, 0xB9], eui);
+        assert!(!decode_hex("00bb", &mut eui));
+        assert!(!decode_hex("00bb7c95adb530bx", &mut eui));
+    }
+
+    #[test]
+    fn encode_report() {
+        let diagnostics = Diagnostics {
+            firmware: FirmwareInfo {
+                major: 2,
+                minor: 0,
+                patch: 3,
+                build: 0,
+            },
+            band: LoraRegion::US915,
+            mode: LoraMode::WAN,
+            connect_mode: ConnectMode::OTAA,
+            joined: true,
+            device_eui: [1; 8],
+            app_eui: [2; 8],
+            channel_mask: 0x00ff,
+            data_rate: 3,
+            status: LinkStatus {
+                rssi: -47,
+                snr: -7,
+                ..Default::default()
+            },
+            metrics: MetricsSummary {
+                commands: 70_000,
+                ..Default::default()
+            },
+        };
+        let mut buf = [0; DIAGNOSTICS_LEN];
+        assert_eq!(DIAGNOSTICS_LEN, diagnostics.encode(&mut buf).unwrap());
+        assert_eq!([1, 2, 0, 3, 0, 1, 0x04], buf[..7]);
+        assert_eq!([0x00, 0xff, 3], buf[23..26]);
+        assert_eq!(-47, buf[31] as i8);
+        assert_eq!(-7, buf[32] as i8);
+        assert_eq!([0xff, 0xff], buf[33..35]);
+        assert!(diagnostics.encode(&mut buf[..10]).is_err());
+    }
+}
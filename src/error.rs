@@ -1,8 +1,20 @@
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum DriverError {
     WriteError,
     ReadError,
     NotInitialized,
     OtherError,
     UnexpectedResponse,
+    JoinFailed,
+    NoPendingOperation,
+    RollbackFailed,
+    BufferTooSmall,
+    /// The configuration option is not valid in the current region.
+    InvalidConfig,
+    /// The payload exceeds the maximum size, given in bytes.
+    PayloadTooLarge(usize),
+    /// The uplink exceeds airtime limits. Carries the earliest time in milliseconds it may be
+    /// sent, or `None` if it never may.
+    DutyCycleLimited(Option<u64>),
 }
@@ -0,0 +1,342 @@
+use crate::regions;
+use crate::{ConnectMode, DriverError, Rak811Driver, Response};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::{serial::Read, serial::Write};
+use heapless::ArrayLength;
+
+/// Data rate stepping applied between failed join attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct DataRateStepping {
+    /// Data rate used for the first join attempt.
+    pub initial: u8,
+    /// Most robust data rate to step down to.
+    pub min: u8,
+}
+
+/// Retry policy for joining a LoRaWAN network.
+#[derive(Debug, Clone, Copy)]
+pub struct JoinConfig {
+    pub mode: ConnectMode,
+    /// Maximum number of join attempts. `None` will retry forever.
+    pub max_attempts: Option<u16>,
+    /// Backoff after the first failed attempt, in milliseconds.
+    pub initial_backoff_ms: u32,
+    /// Upper bound for the backoff, in milliseconds.
+    pub max_backoff_ms: u32,
+    pub data_rate: Option<DataRateStepping>,
+    /// Try each sub-band in turn in US915 and AU915, starting from the first. After a failed
+    /// attempt, the next sub-band is tried right away, and the backoff and data rate stepping
+    /// are applied once all sub-bands have been tried. Ignored in other regions.
+    pub scan_sub_bands: bool,
+}
+
+impl Default for JoinConfig {
+    fn default() -> Self {
+        JoinConfig {
+            mode: ConnectMode::OTAA,
+            max_attempts: None,
+            initial_backoff_ms: 10_000,
+            max_backoff_ms: 3_600_000,
+            data_rate: None,
+            scan_sub_bands: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinStatus {
+    NotJoined,
+    /// A join request has been sent and the driver awaits the outcome.
+    Joining,
+    /// Waiting for backoff to expire. Next attempt is allowed at the given time in milliseconds.
+    Backoff(u64),
+    Joined,
+    /// Maximum number of attempts reached.
+    Failed,
+}
+
+/// Joins a network using the driver, retrying with randomised exponential backoff.
+///
+/// The manager does not keep time itself: the application passes the current time in
+/// milliseconds to `poll`, which returns `nb::Error::WouldBlock` while waiting for the next attempt.
+pub struct JoinManager {
+    config: JoinConfig,
+    status: JoinStatus,
+    attempts: u16,
+    // Failed attempts on every sub-band, or failed attempts when not scanning
+    rounds: u16,
+    data_rate: Option<u8>,
+    sub_band: Option<u8>,
+    rng: u32,
+}
+
+impl JoinManager {
+    /// Create a new join manager. The seed is used to randomise the backoff, and should
+    /// differ between devices (for instance derived from the device EUI or a hardware RNG).
+    pub fn new(config: JoinConfig, seed: u32) -> Self {
+        JoinManager {
+            config,
+            status: JoinStatus::NotJoined,
+            attempts: 0,
+            rounds: 0,
+            data_rate: config.data_rate.map(|d| d.initial),
+            sub_band: if config.scan_sub_bands { Some(1) } else { None },
+            rng: if seed == 0 { 0x2545_f491 } else { seed },
+        }
+    }
+
+    pub fn status(&self) -> JoinStatus {
+        self.status
+    }
+
+    pub fn is_joined(&self) -> bool {
+        self.status == JoinStatus::Joined
+    }
+
+    /// Number of failed join attempts so far.
+    pub fn attempts(&self) -> u16 {
+        self.attempts
+    }
+
+    /// Sub-band used for join attempts when scanning. Once joined, this is the sub-band that
+    /// worked, which is tried first when joining again.
+    pub fn sub_band(&self) -> Option<u8> {
+        self.sub_band
+    }
+
+    /// Forget the join state, for instance after the module has been reset. The sub-band
+    /// found by scanning is kept.
+    pub fn reset(&mut self) {
+        self.status = JoinStatus::NotJoined;
+        self.attempts = 0;
+        self.rounds = 0;
+        self.data_rate = self.config.data_rate.map(|d| d.initial);
+    }
+
+    /// Start a join attempt if not joined and no backoff is pending, or check the outcome
+    /// of an ongoing attempt.
+    pub fn poll<W, R, RST, BUF, RXQ, CMD>(
+        &mut self,
+        driver: &mut Rak811Driver<W, R, RST, BUF, RXQ, CMD>,
+        now_ms: u64,
+    ) -> nb::Result<(), DriverError>
+    where
+        W: Write<u8>,
+        R: Read<u8>,
+        RST: OutputPin,
+        BUF: ArrayLength<u8>,
+        RXQ: ArrayLength<Response>,
+        CMD: ArrayLength<u8>,
+    {
+        let sub_bands = regions::sub_band_count(driver.lora_band());
+        if sub_bands == 0 {
+            // Nothing to scan in this region
+            self.sub_band = None;
+        }
+        match self.status {
+            JoinStatus::Joined => return Ok(()),
+            JoinStatus::Failed => return Err(nb::Error::Other(DriverError::JoinFailed)),
+            JoinStatus::Backoff(at) if now_ms < at => return Err(nb::Error::WouldBlock),
+            JoinStatus::Joining => {}
+            _ => {
+                if let Some(dr) = self.data_rate {
+                    driver.set_data_rate(dr).map_err(nb::Error::Other)?;
+                }
+                if let Some(sub_band) = self.sub_band {
+                    driver.set_sub_band(sub_band).map_err(nb::Error::Other)?;
+                }
+                driver
+                    .start_join(self.config.mode)
+                    .map_err(|e| self.failed(e, now_ms, sub_bands))?;
+                self.status = JoinStatus::Joining;
+            }
+        }
+
+        match driver.poll_join() {
+            Ok(_) => {
+                info!("Joined network after {} failed attempts", self.attempts);
+                self.status = JoinStatus::Joined;
+                Ok(())
+            }
+            Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(nb::Error::Other(e)) => Err(self.failed(e, now_ms, sub_bands)),
+        }
+    }
+
+    // Record a failed attempt and schedule the next one.
+    fn failed(&mut self, e: DriverError, now_ms: u64, sub_bands: u8) -> nb::Error<DriverError> {
+        self.attempts = self.attempts.saturating_add(1);
+        if matches!(self.config.max_attempts, Some(max) if self.attempts >= max) {
+            self.status = JoinStatus::Failed;
+            return nb::Error::Other(e);
+        }
+        if self.next_sub_band(sub_bands) {
+            self.rounds += 1;
+            let delay = self.next_backoff();
+            info!(
+                "Join attempt {} failed ({:?}), retrying in {} ms",
+                self.attempts, e, delay
+            );
+            self.status = JoinStatus::Backoff(now_ms + delay as u64);
+            self.step_data_rate();
+        } else {
+            // Sub-bands left in this round are tried right away
+            info!(
+                "Join attempt {} failed ({:?}), trying sub-band {:?}",
+                self.attempts, e, self.sub_band
+            );
+            self.status = JoinStatus::Backoff(now_ms);
+        }
+        match e {
+            DriverError::JoinFailed => nb::Error::WouldBlock,
+            e => nb::Error::Other(e),
+        }
+    }
+
+    // Move on to the next sub-band when scanning, returning true if all have been tried.
+    fn next_sub_band(&mut self, sub_bands: u8) -> bool {
+        match self.sub_band {
+            Some(sub_band) if sub_band < sub_bands => {
+                self.sub_band = Some(sub_band + 1);
+                false
+            }
+            Some(_) => {
+                self.sub_band = Some(1);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn step_data_rate(&mut self) {
+        if let (Some(dr), Some(stepping)) = (self.data_rate, self.config.data_rate) {
+            if dr > stepping.min {
+                self.data_rate = Some(dr - 1);
+            }
+        }
+    }
+
+    // Exponential backoff with equal jitter: half of the delay is fixed, the other half random.
+    fn next_backoff(&mut self) -> u32 {
+        let shift = core::cmp::min(self.rounds.saturating_sub(1), 31) as u32;
+        let delay = self
+            .config
+            .initial_backoff_ms
+            .checked_mul(1 << shift)
+            .unwrap_or(self.config.max_backoff_ms)
+            .min(self.config.max_backoff_ms);
+        let half = delay / 2;
+        half + self.random() % (delay - half + 1)
+    }
+
+    fn random(&mut self) -> u32 {
+        // xorshift32
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_bounded() {
+        let config = JoinConfig {
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 8000,
+            ..Default::default()
+        };
+        let mut manager = JoinManager::new(config, 42);
+        for attempt in 1..40 {
+            manager.rounds = attempt;
+            let expected = core::cmp::min(1000u32 << core::cmp::min(attempt - 1, 20), 8000);
+            let delay = manager.next_backoff();
+            assert!(delay >= expected / 2);
+            assert!(delay <= expected);
+        }
+    }
+
+    #[test]
+    fn data_rate_steps_down_to_min() {
+        let config = JoinConfig {
+            data_rate: Some(DataRateStepping { initial: 2, min: 0 }),
+            ..Default::default()
+        };
+        let mut manager = JoinManager::new(config, 1);
+        manager.step_data_rate();
+        manager.step_data_rate();
+        manager.step_data_rate();
+        assert_eq!(Some(0), manager.data_rate);
+        manager.reset();
+        assert_eq!(Some(2), manager.data_rate);
+    }
+
+    #[test]
+    fn sub_bands_are_scanned_in_turn() {
+        let config = JoinConfig {
+            scan_sub_bands: true,
+            ..Default::default()
+        };
+        let mut manager = JoinManager::new(config, 1);
+        assert_eq!(Some(1), manager.sub_band());
+        for sub_band in 2..=8 {
+            assert!(!manager.next_sub_band(8));
+            assert_eq!(Some(sub_band), manager.sub_band());
+        }
+        assert!(manager.next_sub_band(8));
+        assert_eq!(Some(1), manager.sub_band());
+
+        manager.sub_band = Some(3);
+        manager.reset();
+        assert_eq!(Some(3), manager.sub_band());
+    }
+
+    // Logging through defmt needs a global logger not available on the host.
+    #[cfg(not(feature = "defmt"))]
+    #[test]
+    fn sub_bands_are_not_scanned_in_other_regions() {
+        let module = crate::mock::Module::new();
+        let mut driver = module.driver().unwrap();
+        let config = JoinConfig {
+            scan_sub_bands: true,
+            ..Default::default()
+        };
+        let mut manager = JoinManager::new(config, 1);
+        module.expect("at+join=otaa", "OK\r\n");
+        assert!(matches!(
+            manager.poll(&mut driver, 0),
+            Err(nb::Error::WouldBlock)
+        ));
+        module.done();
+        assert_eq!(None, manager.sub_band());
+    }
+
+    // Logging through defmt needs a global logger not available on the host.
+    #[cfg(not(feature = "defmt"))]
+    #[test]
+    fn backoff_follows_each_round_of_sub_bands() {
+        let config = JoinConfig {
+            initial_backoff_ms: 1000,
+            scan_sub_bands: true,
+            data_rate: Some(DataRateStepping { initial: 2, min: 0 }),
+            ..Default::default()
+        };
+        let mut manager = JoinManager::new(config, 1);
+        for sub_band in 2..=8 {
+            manager.failed(DriverError::JoinFailed, 100, 8);
+            assert_eq!(JoinStatus::Backoff(100), manager.status());
+            assert_eq!(Some(sub_band), manager.sub_band());
+            assert_eq!(Some(2), manager.data_rate);
+        }
+        manager.failed(DriverError::JoinFailed, 100, 8);
+        assert!(matches!(manager.status(), JoinStatus::Backoff(at) if (600..=1100).contains(&at)));
+        assert_eq!(Some(1), manager.sub_band());
+        assert_eq!(Some(1), manager.data_rate);
+        assert_eq!(8, manager.attempts());
+    }
+}
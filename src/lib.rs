@@ -47,6 +47,43 @@
 //!driver .set_app_key(&[0x00]).unwrap();
 //!```
 //!
+//!Keys are redacted from log output, and buffers holding them are wiped after use. To never log the contents
+//!of commands and responses, enable the `no-command-log` feature.
+//!
+//!Diagnostics are logged using the `log` crate by default. Enable the `defmt` feature to log using `defmt` instead.
+//!
+//!To limit wear of the module EEPROM, the credentials are only written if they differ from the stored values.
+//!The number of writes performed is reported by `config_writes()`.
+//!
+//!Alternatively, the whole configuration can be applied in one call. Current values are read back from the
+//!module and only the settings that differ are written:
+//!
+//!```rust
+//!let diff = driver
+//!    .apply(&rak811::Rak811Config {
+//!        band: Some(rak811::LoraRegion::EU868),
+//!        mode: Some(rak811::LoraMode::WAN),
+//!        device_eui: Some(&dev_eui),
+//!        app_eui: Some(&app_eui),
+//!        app_key: Some(&app_key),
+//!        ..Default::default()
+//!    })
+//!    .unwrap();
+//!```
+//!
+//!The buffer sizes used by the driver can be tuned through type parameters: the parse buffer size, the number of
+//!queued responses and the command buffer size. The defaults are 512 bytes, 4 responses and 128 bytes.
+//!The command buffer must hold the longest command sent: setting a key takes 54 bytes, and an uplink takes 14
+//!bytes plus two per payload byte. Commands which do not fit fail with `DriverError::BufferTooSmall`, and
+//!`max_payload_len` is limited accordingly:
+//!
+//!```rust
+//!use heapless::consts::{U128, U2, U96};
+//!
+//!let driver: rak811::Rak811Driver<_, _, _, U128, U2, U96> =
+//!    rak811::Rak811Driver::new(uarte_tx, uarte_rx, rst).unwrap();
+//!```
+//!
 //!To join the network and send packets:
 //!
 //!```rust
@@ -55,48 +92,269 @@
 //!// Port number can be between 1 and 255
 //!driver.send(lora::QoS::Confirmed, 1, b"hello!").unwrap();
 //!```
+//!
+//!`send` returns an `UplinkResult`, telling whether a confirmed uplink was acknowledged. A downlink received for the
+//!uplink is included, which suits request and response patterns. With `set_uplink_status(true)`, the result also
+//!reports how many transmissions the uplink took and the signal quality of the reply, at the cost of reading the link
+//!status from the module before and after each confirmed uplink:
+//!
+//!```rust
+//!let result = driver.send(lora::QoS::Confirmed, 1, b"ping").unwrap();
+//!if let Some(downlink) = result.downlink {
+//!    let reply = driver.payload(&downlink.data);
+//!    // ...
+//!    driver.release(downlink.data);
+//!}
+//!```
+//!
+//!Payloads larger than allowed for the region and data rate are rejected with `DriverError::PayloadTooLarge`, which
+//!carries the maximum size. The limit is also reported by `max_payload_len()`.
+//!
+//!The `regions` module provides the regional parameters used for validation, such as the modulation and payload
+//!limit of each data rate, transmit power levels, default channels and RX2 settings.
+//!
+//!The time an uplink occupies the air at the current data rate is computed by `time_on_air(len)`, in microseconds:
+//!
+//!```rust
+//!let airtime_us = driver.time_on_air(12).unwrap();
+//!```
+//!
+//!An `UplinkScheduler` tracks the airtime of uplinks, and defers or rejects uplinks that would exceed the duty
+//!cycle limits of the region or a fair access policy such as the 30 seconds per day of TTN:
+//!
+//!```rust
+//!let mut scheduler = UplinkScheduler::new(LoraRegion::EU868, SchedulerConfig::default());
+//!nb::block!(scheduler.send(&mut driver, now_ms, QoS::Unconfirmed, 1, b"hello")).unwrap();
+//!```
+//!
+//!Received data is kept in a payload pool inside the driver, sized like the parse buffer. It can be copied out
+//!using `try_recv`, or read in place using `try_recv_with`. When the pool is full, the oldest data is evicted.
+//!
+//!```rust
+//!driver
+//!    .try_recv_with(1, |data| {
+//!        // handle data
+//!    })
+//!    .unwrap();
+//!```
+//!
+//!A Class A device only receives after it transmits. When the network has queued several downlinks, they can be
+//!drained using `poll_downlinks`, which sends one byte uplinks on the given port until no more downlinks arrive or the
+//!limit is reached, stopping early if the receive queue is full. The downlinks are then read using `try_recv`:
+//!
+//!```rust
+//!let received = driver.poll_downlinks(1, 8).unwrap();
+//!```
+//!
+//!Downlinks the module reports as repeated are suppressed by default. Use `set_repeated_downlinks` to deliver them
+//!instead, marked as repeated when read using `next_downlink`. To also catch duplicates the module does not report,
+//!a `DedupWindow` remembers hashes of the last payloads of each port:
+//!
+//!```rust
+//!let mut dedup: rak811::DedupWindow = rak811::DedupWindow::new(4);
+//!if let Some(downlink) = driver.next_downlink().unwrap() {
+//!    let data = driver.payload(&downlink.data).unwrap_or(&[]);
+//!    if !dedup.is_duplicate(downlink.port, data) {
+//!        // handle data
+//!    }
+//!    driver.release(downlink.data);
+//!}
+//!```
+//!
+//!Devices which must receive at any time can be set to class C using `set_class`, before joining. Downlinks may then
+//!arrive asynchronously, also while a command is in flight: they are queued instead of being taken as the response,
+//!and read using `try_recv` or `next_downlink`. Downlinks are only read from the UART while the driver is used, so
+//!call `try_recv` or `process`/`digest` regularly, and increase the `RXQ` queue size if bursts are expected.
+//!
+//!```rust
+//!driver.set_class(rak811::DeviceClass::C).unwrap();
+//!driver.join(lora::ConnectMode::OTAA).unwrap();
+//!loop {
+//!    if let Some(downlink) = driver.next_downlink().unwrap() {
+//!        // handle downlink
+//!        driver.release(downlink.data);
+//!    }
+//!}
+//!```
+//!
+//!Both `join` and `send` block until the module reports the outcome. To keep servicing other work in the meantime,
+//!use `start_join`/`poll_join` and `start_send`/`poll_send`, where the poll functions return `nb::Error::WouldBlock`
+//!until the operation completes.
+//!
+//!The `Rak811` wrapper tracks the configuration and join state in its type, so that for instance sending before
+//!joining fails to compile:
+//!
+//!```rust
+//!let node = rak811::Rak811::new(driver)
+//!    .into_lorawan(rak811::LoraRegion::EU868)
+//!    .map_err(|(_, e)| e)
+//!    .unwrap();
+//!let mut node = node.join(rak811::ConnectMode::OTAA).map_err(|(_, e)| e).unwrap();
+//!node.send(rak811::QoS::Confirmed, 1, b"hello!").unwrap();
+//!```
+//!
+//!To retry joining with randomised exponential backoff, drive a `JoinManager` from the application loop:
+//!
+//!```rust
+//!let mut join = rak811::JoinManager::new(rak811::JoinConfig::default(), seed);
+//!loop {
+//!    match join.poll(&mut driver, now_ms()) {
+//!        Ok(_) => break,
+//!        Err(nb::Error::WouldBlock) => { /* do other work */ }
+//!        Err(nb::Error::Other(e)) => { /* handle error */ }
+//!    }
+//!}
+//!```
+//!
+//!In US915 and AU915, `set_sub_band(n)` enables only the channels of the sub-band the gateway listens on. If it is
+//!not known, set `scan_sub_bands` in the `JoinConfig` to try each sub-band in turn. The one that worked is reported
+//!by `JoinManager::sub_band()`.
+//!
+//!Channels beyond the defaults of the region can be added using `set_channel`, and removed, enabled or disabled by
+//!index. Entries are validated against the region. The current channel plan is read using `channel_list()`:
+//!
+//!```rust
+//!driver
+//!    .set_channel(rak811::Channel {
+//!        index: 3,
+//!        enabled: true,
+//!        frequency: 867_100_000,
+//!        min_dr: 0,
+//!        max_dr: 5,
+//!    })
+//!    .unwrap();
+//!let channels = driver.channel_list().unwrap();
+//!```
+//!
+//!The whole list is received at once, using about 21 bytes per channel. The 72 channels of US915 and AU915 take
+//!about 1.5 kB, more than the default parse buffer of 512 bytes, so use a `BUF` of `U2048` to read them.
+//!
+//!Where network controlled ADR adapts too slowly, such as for mobile assets, a `RateController` can select the data
+//!rate instead. It turns ADR off, steps the data rate up or down based on the signal margin of acknowledgements, and
+//!falls back to the most robust data rate after consecutive missed acknowledgements:
+//!
+//!```rust
+//!let mut rate = rak811::RateController::new(rak811::RateConfig::default());
+//!rate.start(&mut driver).unwrap();
+//!rate.send(&mut driver, 1, b"hello!").unwrap();
+//!```
+//!
+//!Commands not modelled by the driver can be sent using `send_raw`, which returns the response lines:
+//!
+//!```rust
+//!let mut buf = [0; 256];
+//!let response = driver.send_raw("at+get_config=lora:status", &mut buf).unwrap();
+//!for line in response.lines() {
+//!    // ...
+//!}
+//!```
+//!
+//!Responses can also be turned into `Response::Custom` values by registering nom parsers, which are tried
+//!before the builtin ones:
+//!
+//!```rust
+//!fn parse_battery(input: &[u8]) -> nom::IResult<&[u8], rak811::Response> { /* ... */ }
+//!
+//!driver.register_parser(parse_battery).unwrap();
+//!let response = driver.send_command(rak811::Command::Raw("at+get_config=device:status")).unwrap();
+//!```
 
+use core::marker::PhantomData;
+use core::mem::discriminant;
 use embedded_hal::digital::v2::OutputPin;
 use embedded_hal::{serial::Read, serial::Write};
+
+mod fmt;
+
+mod adr;
 mod buffer;
+mod channel;
+mod config;
+mod dedup;
+mod diagnostics;
 mod error;
+mod join;
+mod metrics;
 mod parser;
+mod pool;
 mod protocol;
+pub mod regions;
+mod scheduler;
+mod typestate;
 
+// Driver tests log through defmt, which needs a global logger not available on the host.
+#[cfg(all(test, not(feature = "defmt")))]
+mod mock;
+
+pub use adr::*;
 pub use buffer::*;
+pub use channel::*;
+pub use config::*;
+pub use dedup::*;
+pub use diagnostics::*;
 pub use drogue_lora::*;
 pub use error::*;
 use heapless::consts;
-use heapless::spsc::Queue;
+use heapless::{ArrayLength, String, Vec};
+pub use join::*;
+pub use metrics::*;
+pub use pool::*;
 pub use protocol::*;
+pub use scheduler::*;
+pub use typestate::*;
 
-const RECV_BUFFER_LEN: usize = 256;
+// Length of `at+send=<qos>,<port>,` preceding the hex encoded payload.
+const SEND_COMMAND_OVERHEAD: usize = 14;
 
-pub struct Rak811Driver<W, R, RST>
+/// Driver for the RAK811 module. The size of the parse buffer, the number of responses that can be
+/// queued and the size of the command buffer are set by the `BUF`, `RXQ` and `CMD` parameters.
+pub struct Rak811Driver<W, R, RST, BUF = consts::U512, RXQ = consts::U4, CMD = consts::U128>
 where
     W: Write<u8>,
     R: Read<u8>,
     RST: OutputPin,
+    BUF: ArrayLength<u8>,
+    RXQ: ArrayLength<Response>,
+    CMD: ArrayLength<u8>,
 {
     tx: W,
     rx: R,
-    parse_buffer: Buffer,
-    rxq: Queue<Response, consts::U4>,
+    parse_buffer: Buffer<BUF>,
+    rxq: Vec<Response, RXQ>,
+    pool: PayloadPool<BUF>,
     connect_mode: ConnectMode,
     lora_mode: LoraMode,
     lora_band: LoraRegion,
+    pending: Option<PendingOperation>,
+    joined: bool,
+    data_rate: Option<u8>,
+    repeated_downlinks: RepeatedDownlinks,
+    uplink_status: bool,
+    config_writes: u32,
+    metrics: Option<DriverMetrics>,
     rst: RST,
+    _command: PhantomData<CMD>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PendingOperation {
+    Join,
+    // Number of downlinks queued before the uplink, and link status before a confirmed uplink
+    Send(QoS, usize, Option<LinkStatus>),
 }
 
-impl<W, R, RST> Rak811Driver<W, R, RST>
+impl<W, R, RST, BUF, RXQ, CMD> Rak811Driver<W, R, RST, BUF, RXQ, CMD>
 where
     W: Write<u8>,
     R: Read<u8>,
     RST: OutputPin,
+    BUF: ArrayLength<u8>,
+    RXQ: ArrayLength<Response>,
+    CMD: ArrayLength<u8>,
 {
     /// Create a new instance of the driver. The driver will trigger a reset of the module
     /// and expect a response from the firmware.
-    pub fn new(tx: W, rx: R, rst: RST) -> Result<Rak811Driver<W, R, RST>, DriverError> {
+    pub fn new(tx: W, rx: R, rst: RST) -> Result<Self, DriverError> {
         let mut driver = Rak811Driver {
             tx,
             rx,
@@ -105,14 +363,24 @@ where
             connect_mode: ConnectMode::OTAA,
             lora_mode: LoraMode::WAN,
             lora_band: LoraRegion::EU868,
-            rxq: Queue::new(),
+            pending: None,
+            joined: false,
+            data_rate: None,
+            repeated_downlinks: RepeatedDownlinks::Suppress,
+            uplink_status: false,
+            config_writes: 0,
+            metrics: None,
+            rxq: Vec::new(),
+            pool: PayloadPool::new(),
+            _command: PhantomData,
         };
 
         driver.initialize()?;
         Ok(driver)
     }
 
-    /// Initialize the driver. This will cause the RAK811 module to be reset.
+    /// Initialize the driver. This will cause the RAK811 module to be reset. Queued events and
+    /// downlinks are discarded, and handles to data in the payload pool are invalidated.
     pub fn initialize(&mut self) -> Result<(), DriverError> {
         self.rst.set_high().ok();
         self.rst.set_low().ok();
@@ -120,6 +388,10 @@ where
         match response {
             Response::Initialized(band) => {
                 self.lora_band = band;
+                self.pending = None;
+                self.joined = false;
+                self.data_rate = None;
+                self.discard_queued();
                 Ok(())
             }
             _ => Err(DriverError::NotInitialized),
@@ -127,7 +399,8 @@ where
     }
 
     /// Send reset command to lora module. Depending on the mode, this will restart
-    /// the module or reload its configuration from EEPROM.
+    /// the module or reload its configuration from EEPROM. As with `initialize`, queued
+    /// events and downlinks are discarded.
     pub fn reset(&mut self, mode: ResetMode) -> Result<(), DriverError> {
         let response = self.send_command(Command::Reset(mode))?;
         match response {
@@ -136,6 +409,10 @@ where
                 match response {
                     Response::Initialized(band) => {
                         self.lora_band = band;
+                        self.pending = None;
+                        self.joined = false;
+                        self.data_rate = None;
+                        self.discard_queued();
                         Ok(())
                     }
                     _ => Err(DriverError::NotInitialized),
@@ -145,19 +422,61 @@ where
         }
     }
 
+    // Drop responses received before the module was reset, and release their data.
+    fn discard_queued(&mut self) {
+        if !self.rxq.is_empty() {
+            debug!("Discarding {} queued responses", self.rxq.len());
+        }
+        self.rxq.clear();
+        self.pool.clear();
+    }
+
     /// Join a LoRa Network using the specified mode.
     pub fn join(&mut self, mode: ConnectMode) -> Result<(), DriverError> {
+        self.start_join(mode)?;
+        nb::block!(self.poll_join())
+    }
+
+    /// Start joining a LoRa Network using the specified mode. The outcome of the join
+    /// must be retrieved using `poll_join`.
+    pub fn start_join(&mut self, mode: ConnectMode) -> Result<(), DriverError> {
         self.connect_mode = mode;
+        self.joined = false;
+        self.data_rate = None;
         let response = self.send_command(Command::Join(mode))?;
         match response {
             Response::Ok => {
-                let response = self.recv_response()?;
+                self.pending = Some(PendingOperation::Join);
+                Ok(())
+            }
+            r => log_unexpected(r),
+        }
+    }
+
+    /// Check if a join started with `start_join` has completed.
+    pub fn poll_join(&mut self) -> nb::Result<(), DriverError> {
+        if !matches!(self.pending, Some(PendingOperation::Join)) {
+            return Err(nb::Error::Other(DriverError::NoPendingOperation));
+        }
+        self.process()?;
+        self.digest()?;
+        let response =
+            self.take_event(|c| matches!(c, EventCode::JoinedSuccess | EventCode::JoinedFailed));
+        match response {
+            None => Err(nb::Error::WouldBlock),
+            Some(response) => {
+                self.pending = None;
                 match response {
-                    Response::Recv(EventCode::JoinedSuccess, _, _, _) => Ok(()),
-                    r => log_unexpected(r),
+                    Response::Recv(EventCode::JoinedSuccess, _, _, _) => {
+                        self.joined = true;
+                        Ok(())
+                    }
+                    Response::Recv(EventCode::JoinedFailed, _, _, _) => {
+                        Err(nb::Error::Other(DriverError::JoinFailed))
+                    }
+                    r => log_unexpected(r).map_err(nb::Error::Other),
                 }
             }
-            r => log_unexpected(r),
         }
     }
 
@@ -185,99 +504,745 @@ where
         }
     }
 
+    /// Set the device address. Like the other credential setters, the address is only
+    /// written if it differs from the value stored in the module, to limit EEPROM wear.
     pub fn set_device_address(&mut self, addr: &DevAddr) -> Result<(), DriverError> {
-        let response = self.send_command(Command::SetConfig(ConfigOption::DevAddr(addr)))?;
+        self.update_config(ConfigOption::DevAddr(addr)).map(|_| ())
+    }
+
+    pub fn set_device_eui(&mut self, eui: &EUI) -> Result<(), DriverError> {
+        self.update_config(ConfigOption::DevEui(eui)).map(|_| ())
+    }
+
+    pub fn set_app_eui(&mut self, eui: &EUI) -> Result<(), DriverError> {
+        self.update_config(ConfigOption::AppEui(eui)).map(|_| ())
+    }
+
+    pub fn set_app_key(&mut self, key: &AppKey) -> Result<(), DriverError> {
+        self.update_config(ConfigOption::AppKey(key)).map(|_| ())
+    }
+
+    pub fn set_apps_key(&mut self, key: &AppsKey) -> Result<(), DriverError> {
+        self.update_config(ConfigOption::AppsKey(key)).map(|_| ())
+    }
+
+    pub fn set_nwks_key(&mut self, key: &NwksKey) -> Result<(), DriverError> {
+        self.update_config(ConfigOption::NwksKey(key)).map(|_| ())
+    }
+
+    /// Set the data rate used for join requests and uplinks.
+    pub fn set_data_rate(&mut self, dr: u8) -> Result<(), DriverError> {
+        self.set_config(ConfigOption::Dr(dr))
+    }
+
+    /// Set the device class. In class C, downlinks may arrive at any time, including while a
+    /// command is in flight. They are queued like other downlinks and read using `try_recv`
+    /// or `next_downlink`. The class is only written if it differs from the stored value.
+    pub fn set_class(&mut self, class: DeviceClass) -> Result<(), DriverError> {
+        self.update_config(ConfigOption::Class(class)).map(|_| ())
+    }
+
+    /// Enable only the channels of the sub-band, numbered from 1, by writing the channel masks
+    /// which differ from the stored ones. Only supported in US915 and AU915, where gateways
+    /// commonly listen on a single sub-band.
+    pub fn set_sub_band(&mut self, sub_band: u8) -> Result<(), DriverError> {
+        let band = self.lora_band;
+        if regions::sub_band_mask(band, sub_band, 0).is_none() {
+            return Err(DriverError::InvalidConfig);
+        }
+        let mut id = 0;
+        while let Some(mask) = regions::sub_band_mask(band, sub_band, id) {
+            self.update_config(ConfigOption::ChMask(id, mask))?;
+            id += 1;
+        }
+        Ok(())
+    }
+
+    /// Read the channel list from the module. The response is received at once, so `BUF` must
+    /// hold about 21 bytes per channel: the 72 channels of US915 and AU915 need `U2048`. With a
+    /// smaller buffer, `DriverError::ReadError` is returned.
+    pub fn channel_list(&mut self) -> Result<ChannelList, DriverError> {
+        let response = self.send_command(Command::GetConfig(ConfigKey::ChList))?;
         match response {
-            Response::Ok => Ok(()),
+            Response::Lines(handle) => {
+                let channels = match self.pool.get(&handle).map(core::str::from_utf8) {
+                    Some(Ok(content)) => parse_channel_list(content),
+                    _ => Err(DriverError::UnexpectedResponse),
+                };
+                self.pool.release(handle);
+                channels
+            }
             r => log_unexpected(r),
         }
     }
 
-    pub fn set_device_eui(&mut self, eui: &EUI) -> Result<(), DriverError> {
-        let response = self.send_command(Command::SetConfig(ConfigOption::DevEui(eui)))?;
+    /// Add a channel, or replace the channel with the same index.
+    pub fn set_channel(&mut self, channel: Channel) -> Result<(), DriverError> {
+        self.set_config(ConfigOption::Channel(channel))
+    }
+
+    /// Remove a channel added using `set_channel`. Channels defined by the region can only be
+    /// disabled.
+    pub fn remove_channel(&mut self, index: u8) -> Result<(), DriverError> {
+        self.set_channel(Channel {
+            index,
+            enabled: false,
+            frequency: 0,
+            min_dr: 0,
+            max_dr: 0,
+        })
+    }
+
+    /// Enable the channel with the index, which must be in the channel list of the module.
+    pub fn enable_channel(&mut self, index: u8) -> Result<(), DriverError> {
+        self.update_channel(index, true)
+    }
+
+    /// Disable the channel with the index, which must be in the channel list of the module.
+    pub fn disable_channel(&mut self, index: u8) -> Result<(), DriverError> {
+        self.update_channel(index, false)
+    }
+
+    fn update_channel(&mut self, index: u8, enabled: bool) -> Result<(), DriverError> {
+        let channel = self
+            .channel_list()?
+            .iter()
+            .find(|c| c.index == index && c.frequency != 0)
+            .copied()
+            .ok_or(DriverError::InvalidConfig)?;
+        if channel.enabled == enabled {
+            return Ok(());
+        }
+        self.set_channel(Channel { enabled, ..channel })
+    }
+
+    /// Store a configuration option in the module. The option is written unconditionally.
+    pub fn set_config(&mut self, option: ConfigOption) -> Result<(), DriverError> {
+        self.validate(&option)?;
+        let response = self.send_command(Command::SetConfig(option))?;
         match response {
-            Response::Ok => Ok(()),
+            Response::Ok => {
+                self.config_writes += 1;
+                match option {
+                    ConfigOption::Dr(dr) => self.data_rate = Some(dr),
+                    // The data rate is now controlled by the network
+                    ConfigOption::Adr(true) => self.data_rate = None,
+                    _ => {}
+                }
+                Ok(())
+            }
             r => log_unexpected(r),
         }
     }
-    pub fn set_app_eui(&mut self, eui: &EUI) -> Result<(), DriverError> {
-        let response = self.send_command(Command::SetConfig(ConfigOption::AppEui(eui)))?;
+
+    // Check that the option is valid in the current region, if known.
+    fn validate(&self, option: &ConfigOption) -> Result<(), DriverError> {
+        let band = self.lora_band;
+        let valid = match option {
+            _ if band == LoraRegion::UNKNOWN => true,
+            ConfigOption::Dr(dr) => regions::max_payload_len(band, *dr).is_some(),
+            ConfigOption::PwrLevel(index) => regions::tx_power(band, *index).is_some(),
+            ConfigOption::ChMask(id, _) => (*id as u16 * 16) < regions::max_channels(band) as u16,
+            ConfigOption::Channel(channel) => regions::is_valid_channel(band, channel),
+            _ => true,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(DriverError::InvalidConfig)
+        }
+    }
+
+    /// Number of configuration writes performed by the driver since it was created. Each
+    /// write is stored in the EEPROM of the module.
+    pub fn config_writes(&self) -> u32 {
+        self.config_writes
+    }
+
+    /// Read a configuration value from the module.
+    pub fn get_config(&mut self, key: ConfigKey) -> Result<ConfigValue, DriverError> {
+        let response = self.send_command(Command::GetConfig(key))?;
         match response {
-            Response::Ok => Ok(()),
+            Response::Config(value) => Ok(value),
             r => log_unexpected(r),
         }
     }
 
-    pub fn set_app_key(&mut self, key: &AppKey) -> Result<(), DriverError> {
-        let response = self.send_command(Command::SetConfig(ConfigOption::AppKey(key)))?;
+    /// True if the module has joined a network since it was last reset.
+    pub fn is_joined(&self) -> bool {
+        self.joined
+    }
+
+    /// Read the firmware version of the module.
+    pub fn firmware_version(&mut self) -> Result<FirmwareInfo, DriverError> {
+        let response = self.send_command(Command::QueryFirmwareInfo)?;
         match response {
-            Response::Ok => Ok(()),
+            Response::FirmwareInfo(info) => Ok(info),
             r => log_unexpected(r),
         }
     }
 
-    pub fn set_apps_key(&mut self, key: &AppsKey) -> Result<(), DriverError> {
-        let response = self.send_command(Command::SetConfig(ConfigOption::AppsKey(key)))?;
+    /// Read link counters and signal quality from the module.
+    pub fn get_status(&mut self) -> Result<LinkStatus, DriverError> {
+        let response = self.send_command(Command::GetStatus)?;
         match response {
-            Response::Ok => Ok(()),
+            Response::Status {
+                tx_ok,
+                tx_err,
+                rx_ok,
+                rx_timeout,
+                rx_err,
+                rssi,
+                snr,
+            } => Ok(LinkStatus {
+                tx_ok,
+                tx_err,
+                rx_ok,
+                rx_timeout,
+                rx_err,
+                rssi,
+                snr,
+            }),
             r => log_unexpected(r),
         }
     }
 
-    pub fn set_nwks_key(&mut self, key: &NwksKey) -> Result<(), DriverError> {
-        let response = self.send_command(Command::SetConfig(ConfigOption::NwksKey(key)))?;
+    /// Collect a snapshot of the module configuration, link status and driver metrics.
+    pub fn diagnostics(&mut self) -> Result<Diagnostics, DriverError> {
+        let mut diagnostics = Diagnostics {
+            firmware: self.firmware_version()?,
+            band: self.get_band()?,
+            mode: self.lora_mode,
+            connect_mode: self.connect_mode,
+            joined: self.joined,
+            device_eui: [0; 8],
+            app_eui: [0; 8],
+            channel_mask: 0,
+            data_rate: 0,
+            status: self.get_status()?,
+            metrics: self
+                .metrics
+                .as_ref()
+                .map(MetricsSummary::from)
+                .unwrap_or_default(),
+        };
+
+        if let Some(value) = self.read_config(ConfigKey::DevEui)? {
+            decode_hex(value.as_str(), &mut diagnostics.device_eui);
+        }
+        if let Some(value) = self.read_config(ConfigKey::AppEui)? {
+            decode_hex(value.as_str(), &mut diagnostics.app_eui);
+        }
+        if let Some(value) = self.read_config(ConfigKey::ChMask)? {
+            let mut mask = [0; 2];
+            if decode_hex(value.as_str(), &mut mask) {
+                diagnostics.channel_mask = u16::from_be_bytes(mask);
+            }
+        }
+        if let Some(value) = self.read_config(ConfigKey::Dr)? {
+            diagnostics.data_rate = value.parse().unwrap_or(0);
+        }
+        Ok(diagnostics)
+    }
+
+    // Read a configuration value, returning None if the module does not support reading it.
+    fn read_config(&mut self, key: ConfigKey) -> Result<Option<ConfigValue>, DriverError> {
+        match self.get_config(key) {
+            Ok(value) => Ok(Some(value)),
+            Err(DriverError::UnexpectedResponse) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the frequency band from the module.
+    pub fn get_band(&mut self) -> Result<LoraRegion, DriverError> {
+        let response = self.send_command(Command::GetBand)?;
         match response {
-            Response::Ok => Ok(()),
+            Response::LoraBand(band) => {
+                self.lora_band = band;
+                Ok(band)
+            }
             r => log_unexpected(r),
         }
     }
 
-    /// Transmit data using the specified confirmation mode and given port.
-    pub fn send(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<(), DriverError> {
+    // Frequency band last reported by or set in the module.
+    pub(crate) fn lora_band(&self) -> LoraRegion {
+        self.lora_band
+    }
+
+    /// Apply a configuration to the module. Current values are read back first, and only
+    /// the settings that differ are written. The mode of operation can not be read from the
+    /// module, and is compared with the mode last set through the driver.
+    pub fn apply(&mut self, config: &Rak811Config) -> Result<ConfigDiff, DriverError> {
+        let mut diff = ConfigDiff::default();
+        if let Some(band) = config.band {
+            if self.get_band()? != band {
+                self.set_band(band)?;
+                diff.band = true;
+            }
+        }
+
+        if let Some(mode) = config.mode {
+            if discriminant(&self.lora_mode) != discriminant(&mode) {
+                self.set_mode(mode)?;
+                diff.mode = true;
+            }
+        }
+
+        if let Some(mode) = config.connect_mode {
+            diff.connect_mode = discriminant(&self.connect_mode) != discriminant(&mode);
+            self.connect_mode = mode;
+        }
+
+        for option in config.options() {
+            let key = option.key();
+            if self.update_config(option)? {
+                diff.options.push(key).ok();
+            }
+        }
+        debug!("Applied configuration: {:?}", diff);
+        Ok(diff)
+    }
+
+    /// Apply all options staged in the transaction. The current values are read before
+    /// writing, and options already set to the staged value are skipped. If any write fails,
+    /// the options already written are set back to their previous values. Options whose value
+    /// can not be read back are written last. If a written option can not be restored, the
+    /// configuration is reloaded from EEPROM using `ResetMode::Reload`, and
+    /// `DriverError::RollbackFailed` is returned if that fails too.
+    pub fn commit(&mut self, transaction: &ConfigTransaction) -> Result<(), DriverError> {
+        let mut snapshot: Vec<Option<ConfigValue>, consts::U8> = Vec::new();
+        let mut result = Ok(());
+        for option in transaction.options() {
+            let value = match self.get_config(option.key()) {
+                Ok(value) => Some(value),
+                Err(DriverError::UnexpectedResponse) => None,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            snapshot.push(value).ok();
+        }
+
+        if result.is_ok() {
+            result = self.write_transaction(transaction, &snapshot);
+        }
+        for value in snapshot.iter_mut().flatten() {
+            wipe(value);
+        }
+        result
+    }
+
+    fn write_transaction(
+        &mut self,
+        transaction: &ConfigTransaction,
+        snapshot: &[Option<ConfigValue>],
+    ) -> Result<(), DriverError> {
+        let options = transaction.options();
+        // Options with a known previous value first, as only those can be restored
+        let order = (0..options.len())
+            .filter(|i| snapshot[*i].is_some())
+            .chain((0..options.len()).filter(|i| snapshot[*i].is_none()));
+        let mut written: Vec<usize, consts::U8> = Vec::new();
+        for i in order {
+            let option = options[i];
+            if matches!(&snapshot[i], Some(value) if option.matches(value.as_str())) {
+                continue;
+            }
+            if let Err(e) = self.set_config(option) {
+                warn!("Failed to set {:?}, rolling back", option.key());
+                self.rollback(options, snapshot, &written)?;
+                return Err(e);
+            }
+            written.push(i).ok();
+        }
+        Ok(())
+    }
+
+    // Write back the previous values of the written options, in reverse order, and reload
+    // the configuration if any of them can not be restored.
+    fn rollback(
+        &mut self,
+        options: &[ConfigOption],
+        snapshot: &[Option<ConfigValue>],
+        written: &[usize],
+    ) -> Result<(), DriverError> {
+        let mut restored = true;
+        for &i in written.iter().rev() {
+            let key = options[i].key();
+            let ok = match &snapshot[i] {
+                Some(value) => matches!(
+                    self.send_command(Command::SetConfigValue(key, value.as_str())),
+                    Ok(Response::Ok)
+                ),
+                None => false,
+            };
+            if ok {
+                self.config_writes += 1;
+                if let ConfigKey::Dr | ConfigKey::Adr = key {
+                    self.data_rate = None;
+                }
+            } else {
+                warn!("Unable to restore {:?}", key);
+                restored = false;
+            }
+        }
+        if restored {
+            return Ok(());
+        }
+        warn!("Reloading configuration");
+        self.reset(ResetMode::Reload)
+            .map_err(|_| DriverError::RollbackFailed)
+    }
+
+    // Store a configuration option unless the module already has the same value.
+    // Returns true if the option was written.
+    fn update_config(&mut self, option: ConfigOption) -> Result<bool, DriverError> {
+        let unchanged = match self.get_config(option.key()) {
+            Ok(mut current) => {
+                let unchanged = option.matches(current.as_str());
+                wipe(&mut current);
+                unchanged
+            }
+            Err(DriverError::UnexpectedResponse) => false,
+            Err(e) => return Err(e),
+        };
+        if !unchanged {
+            self.set_config(option)?;
+        }
+        Ok(!unchanged)
+    }
+
+    /// Transmit data using the specified confirmation mode and given port. Any downlink received
+    /// for the uplink is returned in the result instead of being queued for `try_recv`. In class C,
+    /// this may be a downlink which arrived while the uplink was in flight.
+    pub fn send(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<UplinkResult, DriverError> {
+        self.start_send(qos, port, data)?;
+        nb::block!(self.poll_send())
+    }
+
+    /// Start transmitting data using the specified confirmation mode and given port. The
+    /// outcome of the transmission must be retrieved using `poll_send`.
+    pub fn start_send(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<(), DriverError> {
+        let max = self.max_payload_len()?;
+        if data.len() > max {
+            return Err(DriverError::PayloadTooLarge(max));
+        }
+        let status = match qos {
+            QoS::Confirmed if self.uplink_status => self.read_status()?,
+            _ => None,
+        };
+        let downlinks = self.rxq.iter().filter(|r| is_downlink(r)).count();
         let response = self.send_command(Command::Send(qos, port, data))?;
         match response {
             Response::Ok => {
-                let response = self.recv_response()?;
-                let expected_code = match qos {
-                    QoS::Unconfirmed => EventCode::TxUnconfirmed,
-                    QoS::Confirmed => EventCode::TxConfirmed,
-                };
-                match response {
-                    Response::Recv(c, 0, _, _) if expected_code == c => Ok(()),
-                    r => log_unexpected(r),
-                }
+                self.pending = Some(PendingOperation::Send(qos, downlinks, status));
+                Ok(())
             }
             r => log_unexpected(r),
         }
     }
 
+    /// Largest payload that can be sent in the current region at the current data rate, also
+    /// limited by the size of the command buffer. The data rate is read from the module unless
+    /// it was set through the driver. With ADR enabled, the network may lower the data rate at
+    /// any time, so the limit is only a hint.
+    pub fn max_payload_len(&mut self) -> Result<usize, DriverError> {
+        let command_limit = String::<CMD>::new()
+            .capacity()
+            .saturating_sub(SEND_COMMAND_OVERHEAD)
+            / 2;
+        let region_limit = self
+            .current_data_rate()?
+            .and_then(|dr| regions::max_payload_len(self.lora_band, dr));
+        Ok(region_limit.map_or(command_limit, |limit| limit.min(command_limit)))
+    }
+
+    /// Time on air in microseconds of an uplink with `len` bytes of payload at the current
+    /// data rate, including the LoRaWAN overhead. Returns `DriverError::InvalidConfig` if the
+    /// region or data rate is not known.
+    pub fn time_on_air(&mut self, len: usize) -> Result<u32, DriverError> {
+        self.current_data_rate()?
+            .and_then(|dr| regions::time_on_air(self.lora_band, dr, len))
+            .ok_or(DriverError::InvalidConfig)
+    }
+
+    // The data rate last set through the driver, or read from the module.
+    fn current_data_rate(&mut self) -> Result<Option<u8>, DriverError> {
+        if self.data_rate.is_none() {
+            self.data_rate = self
+                .read_config(ConfigKey::Dr)?
+                .and_then(|value| value.parse().ok());
+        }
+        Ok(self.data_rate)
+    }
+
+    /// Check if a transmission started with `start_send` has completed.
+    pub fn poll_send(&mut self) -> nb::Result<UplinkResult, DriverError> {
+        self.finish_send(true)
+    }
+
+    // Check for the outcome of a transmission, leaving any downlink queued unless `dequeue` is set.
+    fn finish_send(&mut self, dequeue: bool) -> nb::Result<UplinkResult, DriverError> {
+        let (qos, downlinks, before) = match self.pending {
+            Some(PendingOperation::Send(qos, downlinks, status)) => (qos, downlinks, status),
+            _ => return Err(nb::Error::Other(DriverError::NoPendingOperation)),
+        };
+        self.process()?;
+        self.digest()?;
+        let response = self.take_event(|c| {
+            matches!(
+                c,
+                EventCode::TxConfirmed
+                    | EventCode::TxUnconfirmed
+                    | EventCode::TxTimeout
+                    | EventCode::Rx2Timeout
+            )
+        });
+        match response {
+            None => Err(nb::Error::WouldBlock),
+            Some(response) => {
+                self.pending = None;
+                let acked = match (qos, response) {
+                    (QoS::Confirmed, Response::Recv(EventCode::TxConfirmed, 0, _, _)) => true,
+                    // No acknowledgement received
+                    (QoS::Confirmed, Response::Recv(EventCode::TxConfirmed, _, _, _))
+                    | (QoS::Confirmed, Response::Recv(EventCode::TxTimeout, _, _, _))
+                    | (QoS::Confirmed, Response::Recv(EventCode::Rx2Timeout, _, _, _)) => false,
+                    (QoS::Unconfirmed, Response::Recv(EventCode::TxUnconfirmed, 0, _, _)) => false,
+                    (_, r) => return log_unexpected(r).map_err(nb::Error::Other),
+                };
+                let received = self.rxq.iter().filter(|r| is_downlink(r)).count() > downlinks;
+                // The outcome is reported even if the status can not be read, and the status is
+                // read before dequeuing the downlink so that nothing is queued after it
+                let after = match qos {
+                    _ if !self.uplink_status => None,
+                    QoS::Unconfirmed if !received => None,
+                    _ => self.read_status().unwrap_or_else(|e| {
+                        warn!("Unable to read status: {:?}", e);
+                        None
+                    }),
+                };
+                let downlink = if dequeue {
+                    self.take_downlink(downlinks)
+                } else {
+                    None
+                };
+                let signal = after.filter(|_| acked || downlink.is_some());
+                Ok(UplinkResult {
+                    acked,
+                    transmissions: match (before, after) {
+                        (Some(before), Some(after)) => {
+                            Some(after.transmissions().wrapping_sub(before.transmissions()))
+                        }
+                        _ => None,
+                    },
+                    rssi: signal.map(|s| s.rssi),
+                    snr: signal.map(|s| s.snr),
+                    downlink,
+                })
+            }
+        }
+    }
+
+    // Dequeue the first downlink received after the given number of queued downlinks.
+    fn take_downlink(&mut self, queued: usize) -> Option<Downlink> {
+        let i = self
+            .rxq
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| is_downlink(r))
+            .nth(queued)
+            .map(|(i, _)| i)?;
+        match self.remove_response(i) {
+            Response::Recv(code, port, _, Some(data)) => Some(Downlink {
+                port,
+                data,
+                repeated: code == EventCode::DownlinkRepeated,
+            }),
+            _ => None,
+        }
+    }
+
+    // Read the link status, returning None if the module does not report it.
+    fn read_status(&mut self) -> Result<Option<LinkStatus>, DriverError> {
+        match self.get_status() {
+            Ok(status) => Ok(Some(status)),
+            Err(DriverError::UnexpectedResponse) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the link status of the module around uplinks, to report the number of
+    /// transmissions and the signal quality in `UplinkResult`. Each confirmed uplink then takes
+    /// an `at+status` round trip before and after the transmission, and each uplink receiving a
+    /// downlink one after it. If the status can not be read after the transmission, the
+    /// outcome is reported without it. Disabled by default.
+    pub fn set_uplink_status(&mut self, enabled: bool) {
+        self.uplink_status = enabled;
+    }
+
+    /// Send unconfirmed uplinks with a single zero byte on the port to drain downlinks queued by
+    /// the network, until an uplink gets no downlink or `limit` uplinks have been sent. The
+    /// firmware does not accept empty payloads, and the module does not report pending frames,
+    /// so at least one uplink is sent. Received downlinks are queued for
+    /// `try_recv`, and their number is returned. Draining stops early once the receive queue
+    /// has no room for another downlink.
+    pub fn poll_downlinks(&mut self, port: Port, limit: usize) -> Result<usize, DriverError> {
+        let mut received = 0;
+        for _ in 0..limit {
+            // Room for a downlink and the outcome of the uplink
+            if self.rxq.capacity() - self.rxq.len() < 2 {
+                debug!("Receive queue full, stopping after {} downlinks", received);
+                break;
+            }
+            let queued = self.rxq.iter().filter(|r| is_downlink(r)).count();
+            self.start_send(QoS::Unconfirmed, port, &[0])?;
+            nb::block!(self.finish_send(false))?;
+            if self.rxq.iter().filter(|r| is_downlink(r)).count() == queued {
+                break;
+            }
+            received += 1;
+        }
+        Ok(received)
+    }
+
+    /// Set how downlinks reported as repeated by the module are handled. By default they are
+    /// suppressed, so that a retransmitted downlink is not applied twice.
+    pub fn set_repeated_downlinks(&mut self, policy: RepeatedDownlinks) {
+        self.repeated_downlinks = policy;
+    }
+
+    /// Poll for a downlink received on any port. Unlike `try_recv`, the downlink tells if it was
+    /// reported as repeated.
+    pub fn next_downlink(&mut self) -> Result<Option<Downlink>, DriverError> {
+        self.process()?;
+        self.digest()?;
+        Ok(self.take_downlink(0))
+    }
+
     /// Poll for any received data and copy it to the provided buffer. If data have been received,
-    /// the length of the data is returned.
+    /// the length of the data is returned. If the buffer is too small, the data is kept and
+    /// `DriverError::BufferTooSmall` is returned.
     pub fn try_recv(&mut self, port: Port, rx_buf: &mut [u8]) -> Result<usize, DriverError> {
+        self.process()?;
         self.digest()?;
-        let mut tries = self.rxq.len();
-        while tries > 0 {
-            match self.rxq.dequeue() {
-                None => return Ok(0),
-                Some(Response::Recv(EventCode::RecvData, p, len, Some(data))) if p == port => {
-                    if len > rx_buf.len() {
-                        self.rxq
-                            .enqueue(Response::Recv(EventCode::RecvData, p, len, Some(data)))
-                            .map_err(|_| DriverError::ReadError)?;
-                    }
+        if let Some(i) = self.find_downlink(port) {
+            if let Response::Recv(_, _, len, _) = self.rxq[i] {
+                if len > rx_buf.len() {
+                    return Err(DriverError::BufferTooSmall);
+                }
+            }
+        }
+        self.try_recv_with(port, |data| {
+            rx_buf[..data.len()].copy_from_slice(data);
+            data.len()
+        })
+        .map(|len| len.unwrap_or(0))
+    }
 
-                    rx_buf[0..len].clone_from_slice(&data);
-                    return Ok(len);
+    /// Poll for any received data and pass it to the closure, reading it in place. The data is
+    /// released afterwards. Returns the result of the closure if data have been received.
+    pub fn try_recv_with<T, F>(&mut self, port: Port, f: F) -> Result<Option<T>, DriverError>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        self.process()?;
+        self.digest()?;
+        match self.find_downlink(port).map(|i| self.remove_response(i)) {
+            Some(Response::Recv(_, _, _, Some(handle))) => {
+                let result = self.pool.get(&handle).map(f);
+                self.pool.release(handle);
+                // Data was evicted to make room for newer responses
+                result.ok_or(DriverError::ReadError).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Read data stored in the payload pool, such as the content of a `Response::Lines` response.
+    /// Returns `None` if the data was evicted to make room for newer responses.
+    pub fn payload(&self, handle: &PayloadHandle) -> Option<&[u8]> {
+        self.pool.get(handle)
+    }
+
+    /// Release data stored in the payload pool. Unreleased data is evicted when the pool is full.
+    pub fn release(&mut self, handle: PayloadHandle) {
+        self.pool.release(handle);
+    }
+
+    fn find_downlink(&self, port: Port) -> Option<usize> {
+        self.rxq
+            .iter()
+            .position(|r| is_downlink(r) && matches!(r, Response::Recv(_, p, _, _) if *p == port))
+    }
+
+    /// Send a command not modelled by the driver, such as `at+get_config=lora:status`, and collect
+    /// the response lines into the provided buffer, separated by `\n`. Collection stops at the
+    /// first line starting with `OK` or `ERROR`. Events received meanwhile are queued as usual.
+    /// Fails with `DriverError::BufferTooSmall` if the command is longer than `CMD`.
+    pub fn send_raw<'b>(
+        &mut self,
+        command: &str,
+        buf: &'b mut [u8],
+    ) -> Result<RawResponse<'b>, DriverError> {
+        if command.len() > String::<CMD>::new().capacity() {
+            return Err(DriverError::BufferTooSmall);
+        }
+        self.parse_buffer.set_raw(true);
+        let result = self
+            .send_command(Command::Raw(command))
+            .and_then(|response| self.collect_lines(response, buf));
+        self.parse_buffer.set_raw(false);
+        let len = result?;
+        let data = core::str::from_utf8(&buf[..len]).map_err(|_| DriverError::ReadError)?;
+        Ok(RawResponse::new(data))
+    }
+
+    fn collect_lines(&mut self, first: Response, buf: &mut [u8]) -> Result<usize, DriverError> {
+        let mut len = 0;
+        let mut response = first;
+        loop {
+            match response {
+                Response::Line(line) if line.is_empty() => {}
+                Response::Line(line) => {
+                    let sep = if len > 0 { 1 } else { 0 };
+                    if len + sep + line.len() > buf.len() {
+                        return Err(DriverError::BufferTooSmall);
+                    }
+                    if sep > 0 {
+                        buf[len] = b'\n';
+                    }
+                    buf[len + sep..len + sep + line.len()].copy_from_slice(line.as_bytes());
+                    len += sep + line.len();
+                    if line.starts_with("OK") || line.starts_with("ERROR") {
+                        return Ok(len);
+                    }
                 }
-                Some(event) => {
-                    self.rxq
-                        .enqueue(event)
-                        .map_err(|_| DriverError::ReadError)?;
+                r => {
+                    self.rxq.push(r).map_err(|_| DriverError::ReadError)?;
                 }
             }
-            tries -= 1;
+            response = loop {
+                self.process()?;
+                self.digest()?;
+                if let Some(line) = self.take_response(|r| matches!(r, Response::Line(_))) {
+                    break line;
+                }
+            };
         }
-        Ok(0)
+    }
+
+    /// Register a parser for responses not modelled by the driver. Registered parsers are
+    /// tried in order before the builtin parsers, so responses to commands sent using
+    /// `send_command(Command::Raw(..))` can be returned as `Response::Custom`. Up to four
+    /// parsers can be registered.
+    pub fn register_parser(&mut self, parser: ResponseParser) -> Result<(), DriverError> {
+        self.parse_buffer
+            .add_parser(parser)
+            .map_err(|_| DriverError::OtherError)
     }
 
     /// Attempt to read data from UART and store it in the parse buffer. This should
@@ -290,9 +1255,12 @@ where
                 }
                 Err(nb::Error::Other(_)) => return Err(DriverError::ReadError),
                 Ok(b) => {
-                    self.parse_buffer
-                        .write(b)
-                        .map_err(|_| DriverError::ReadError)?;
+                    if self.parse_buffer.write(b).is_err() {
+                        if let Some(metrics) = &mut self.metrics {
+                            metrics.overflows = metrics.overflows.wrapping_add(1);
+                        }
+                        return Err(DriverError::ReadError);
+                    }
                 }
             }
         }
@@ -301,19 +1269,111 @@ where
 
     /// Attempt to parse the internal buffer and enqueue any response data found.
     pub fn digest(&mut self) -> Result<(), DriverError> {
-        let result = self.parse_buffer.parse();
+        let result = self.parse_buffer.parse(&mut self.pool);
+        let evicted = self.pool.take_evicted();
+        if let (Some(metrics), true) = (&mut self.metrics, evicted > 0) {
+            metrics.overflows = metrics.overflows.wrapping_add(evicted as u32);
+        }
         if let Ok(response) = result {
             if !matches!(response, Response::None) {
-                log::debug!("Got response: {:?}", response);
-                self.rxq
-                    .enqueue(response)
-                    .map_err(|_| DriverError::ReadError)?;
+                if self.parse_buffer.is_redacted() {
+                    debug!("Got response: <redacted>");
+                } else {
+                    debug!("Got response: {:?}", response);
+                }
+                if let Some(metrics) = &mut self.metrics {
+                    metrics.response_received(&response);
+                }
+                let response = match (response, self.repeated_downlinks) {
+                    (
+                        Response::Recv(EventCode::DownlinkRepeated, _, _, data),
+                        RepeatedDownlinks::Suppress,
+                    ) => {
+                        debug!("Suppressing repeated downlink");
+                        if let Some(data) = data {
+                            self.pool.release(data);
+                        }
+                        return Ok(());
+                    }
+                    (response, _) => response,
+                };
+                if !self.is_awaited(&response) {
+                    debug!("Dropping event nobody awaits");
+                    if let Response::Recv(_, _, _, Some(data)) = response {
+                        self.pool.release(data);
+                    }
+                    return Ok(());
+                }
+                let downlink = is_downlink(&response);
+                if self.rxq.push(response).is_err() {
+                    if let Some(metrics) = &mut self.metrics {
+                        metrics.overflows = metrics.overflows.wrapping_add(1);
+                    }
+                    return Err(DriverError::ReadError);
+                }
+                if let (Some(metrics), true) = (&mut self.metrics, downlink) {
+                    metrics.downlink_queued();
+                }
             }
         }
         Ok(())
     }
 
-    // Block until a response is received.
+    // True unless the response is an event which no pending operation or receive call consumes.
+    fn is_awaited(&self, response: &Response) -> bool {
+        match response {
+            Response::Recv(EventCode::RecvData, _, _, Some(_))
+            | Response::Recv(EventCode::DownlinkRepeated, _, _, Some(_)) => true,
+            Response::Recv(EventCode::JoinedSuccess, _, _, _)
+            | Response::Recv(EventCode::JoinedFailed, _, _, _) => {
+                matches!(self.pending, Some(PendingOperation::Join))
+            }
+            Response::Recv(EventCode::TxConfirmed, _, _, _)
+            | Response::Recv(EventCode::TxUnconfirmed, _, _, _)
+            | Response::Recv(EventCode::TxTimeout, _, _, _)
+            | Response::Recv(EventCode::Rx2Timeout, _, _, _) => {
+                matches!(self.pending, Some(PendingOperation::Send(..)))
+            }
+            Response::Recv(_, _, _, _) => false,
+            _ => true,
+        }
+    }
+
+    // Dequeue the first event matching the predicate, keeping the order of other queued responses.
+    fn take_event<F>(&mut self, f: F) -> Option<Response>
+    where
+        F: Fn(&EventCode) -> bool,
+    {
+        self.take_response(|r| matches!(r, Response::Recv(c, _, _, _) if f(c)))
+    }
+
+    // Dequeue the first response matching the predicate, keeping the order of other queued responses.
+    fn take_response<F>(&mut self, f: F) -> Option<Response>
+    where
+        F: Fn(&Response) -> bool,
+    {
+        let i = self.rxq.iter().position(f)?;
+        Some(self.remove_response(i))
+    }
+
+    fn remove_response(&mut self, index: usize) -> Response {
+        let last = self.rxq.len() - 1;
+        for i in index..last {
+            self.rxq.swap(i, i + 1);
+        }
+        if let (true, Response::Config(value)) =
+            (self.parse_buffer.is_redacted(), &mut self.rxq[last])
+        {
+            // Leave no copy of key material in the vacated slot
+            let response = Response::Config(value.clone());
+            wipe(value);
+            self.rxq.pop();
+            return response;
+        }
+        self.rxq.pop().unwrap()
+    }
+
+    // Block until a response is received. Events are left queued.
     fn recv_response(&mut self) -> Result<Response, DriverError> {
         loop {
             // Run processing to increase likelyhood we have something to parse.
@@ -321,8 +1381,12 @@ where
                 self.process()?;
             }
             self.digest()?;
-            if let Some(response) = self.rxq.dequeue() {
-                return Ok(response);
+            if let Some(i) = self
+                .rxq
+                .iter()
+                .position(|r| !matches!(r, Response::Recv(_, _, _, _)))
+            {
+                return Ok(self.remove_response(i));
             }
         }
     }
@@ -343,26 +1407,471 @@ where
 
     /// Send an AT command to the lora module and await a response.
     pub fn send_command(&mut self, command: Command) -> Result<Response, DriverError> {
-        let mut s = Command::buffer();
-        command.encode(&mut s);
-        log::debug!("Sending command {}", s.as_str());
-        self.do_write(s.as_bytes())?;
-        self.do_write(b"\r\n")?;
+        let mut s: String<CMD> = String::new();
+        let sensitive = command.is_sensitive();
+        if let Err(e) = command.encode(&mut s) {
+            if sensitive {
+                wipe(&mut s);
+            }
+            return Err(e);
+        }
+        if sensitive || cfg!(feature = "no-command-log") {
+            debug!("Sending command <redacted>");
+        } else {
+            debug!("Sending command {}", s.as_str());
+        }
+        self.parse_buffer.set_redact(sensitive);
+        self.parse_buffer.set_multiline(command.is_multiline());
+        let kind = command.kind();
+        let start = self.metrics.as_mut().map(|metrics| {
+            metrics.command_sent(kind, s.len() + 2);
+            metrics.now()
+        });
+        let written = self.do_write(s.as_bytes());
+        if sensitive {
+            wipe(&mut s);
+        }
 
-        let response = self.recv_response()?;
-        Ok(response)
+        // Framing must be reset even if the command could not be written
+        let response = written
+            .and_then(|_| self.do_write(b"\r\n"))
+            .and_then(|_| self.recv_response());
+        self.parse_buffer.set_redact(false);
+        self.parse_buffer.set_multiline(false);
+        if let (Some(metrics), Some(start), Ok(response)) = (&mut self.metrics, start, &response) {
+            metrics.command_completed(kind, start, response);
+        }
+        response
     }
+
+    /// Start collecting metrics. The clock must return the current time in milliseconds,
+    /// and is used to measure command latency.
+    pub fn enable_metrics(&mut self, clock: fn() -> u32) {
+        self.metrics = Some(DriverMetrics::new(clock));
+    }
+
+    /// Metrics collected since enabled or last reset.
+    pub fn metrics(&self) -> Option<&DriverMetrics> {
+        self.metrics.as_ref()
+    }
+
+    pub fn reset_metrics(&mut self) {
+        if let Some(metrics) = &mut self.metrics {
+            metrics.reset();
+        }
+    }
+}
+
+fn is_downlink(r: &Response) -> bool {
+    matches!(
+        r,
+        Response::Recv(EventCode::RecvData, _, _, Some(_))
+            | Response::Recv(EventCode::DownlinkRepeated, _, _, Some(_))
+    )
 }
 
-fn log_unexpected(r: Response) -> Result<(), DriverError> {
-    log::error!("Unexpected response: {:?}", r);
+fn log_unexpected<T>(r: Response) -> Result<T, DriverError> {
+    error!("Unexpected response: {:?}", r);
     Err(DriverError::UnexpectedResponse)
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(feature = "defmt")))]
 mod tests {
+    use super::*;
+    use crate::mock::{self, Module};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn dequeued_keys_are_wiped() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module.expect(
+            "at+get_config=app_key",
+            "OKabababababababababababababababab\r\n",
+        );
+        let value = driver.get_config(ConfigKey::AppKey).unwrap();
+        assert_eq!("abababababababababababababababab", value.as_str());
+        assert!(driver.rxq.is_empty());
+        // Safety: the queue storage is inline, and the vacated slot still holds the response bytes.
+        let slot = unsafe {
+            core::slice::from_raw_parts(
+                driver.rxq.as_ptr() as *const u8,
+                core::mem::size_of::<Response>(),
+            )
+        };
+        assert!(!slot.windows(4).any(|w| w == b"abab"));
+    }
+
+    #[test]
+    fn framing_is_reset_after_write_errors() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module.fail("at+get_config=app_key");
+        assert!(matches!(
+            driver.get_config(ConfigKey::AppKey),
+            Err(DriverError::WriteError)
+        ));
+        assert!(!driver.parse_buffer.is_redacted());
+    }
+
+    #[test]
+    fn unchanged_channel_masks_are_not_written() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        driver.lora_band = LoraRegion::US915;
+        module
+            .expect("at+get_config=ch_mask", "OK0,ff00\r\n")
+            .expect("at+get_config=ch_mask", "OK1,00ff\r\n")
+            .expect("at+set_config=ch_mask:1,0000", "OK\r\n")
+            .expect("at+get_config=ch_mask", "OK2,0000\r\n")
+            .expect("at+get_config=ch_mask", "OK3,0000\r\n")
+            .expect("at+get_config=ch_mask", "OK4,0002\r\n");
+        driver.set_sub_band(2).unwrap();
+        module.done();
+        assert_eq!(1, driver.config_writes());
+    }
+
+    #[test]
+    fn channel_lists_need_a_large_buffer() {
+        use core::fmt::Write as _;
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        let mut reply = String::<consts::U2048>::from("OK");
+        for i in 0..72 {
+            write!(reply, "*{},on,{},0,3;", i, 902_300_000 + i * 200_000).unwrap();
+        }
+        reply.push_str("\r\n").unwrap();
+        module.expect("at+get_config=ch_list", &reply);
+        assert!(matches!(driver.channel_list(), Err(DriverError::ReadError)));
+
+        let module = Module::new();
+        let mut driver = module.driver_with_buffer::<consts::U2048>().unwrap();
+        module.expect("at+get_config=ch_list", &reply);
+        assert_eq!(72, driver.channel_list().unwrap().len());
+    }
+
+    #[test]
+    fn raw_commands_longer_than_the_buffer_are_rejected() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        let command = core::str::from_utf8(&[b'a'; 200]).unwrap();
+        let mut buf = [0; 64];
+        assert!(matches!(
+            driver.send_raw(command, &mut buf),
+            Err(DriverError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn unchanged_credentials_are_not_written() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module.expect("at+get_config=dev_eui", "OK0102030405060708\r\n");
+        driver
+            .set_device_eui(&EUI([1, 2, 3, 4, 5, 6, 7, 8]))
+            .unwrap();
+        module.done();
+        assert_eq!(0, driver.config_writes());
+
+        module
+            .expect(
+                "at+get_config=app_key",
+                "OK00000000000000000000000000000000\r\n",
+            )
+            .expect(
+                "at+set_config=app_key:abababababababababababababababab",
+                "OK\r\n",
+            );
+        driver.set_app_key(&AppKey([0xab; 16])).unwrap();
+        module.done();
+        assert_eq!(1, driver.config_writes());
+    }
+
+    #[test]
+    fn failed_writes_are_not_counted() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=app_eui", "OK0000000000000000\r\n")
+            .expect("at+set_config=app_eui:0102030405060708", "ERROR-1\r\n");
+        assert!(driver.set_app_eui(&EUI([1, 2, 3, 4, 5, 6, 7, 8])).is_err());
+        module.done();
+        assert_eq!(0, driver.config_writes());
+    }
+
+    #[test]
+    fn commit_skips_unchanged_options() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=dr", "OK3\r\n")
+            .expect("at+get_config=adr", "OKon\r\n")
+            .expect("at+set_config=adr:off", "OK\r\n");
+        let mut transaction = ConfigTransaction::new();
+        transaction.stage(ConfigOption::Dr(3)).unwrap();
+        transaction.stage(ConfigOption::Adr(false)).unwrap();
+        driver.commit(&transaction).unwrap();
+        module.done();
+        assert_eq!(1, driver.config_writes());
+    }
+
+    #[test]
+    fn commit_restores_previous_values() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=dr", "OK5\r\n")
+            .expect("at+get_config=adr", "OKon\r\n")
+            .expect("at+get_config=pwr_level", "OK0\r\n")
+            .expect("at+set_config=dr:3", "OK\r\n")
+            .expect("at+set_config=adr:off", "OK\r\n")
+            .expect("at+set_config=pwr_level:1", "ERROR-1\r\n")
+            .expect("at+set_config=adr:on", "OK\r\n")
+            .expect("at+set_config=dr:5", "OK\r\n");
+        let mut transaction = ConfigTransaction::new();
+        transaction.stage(ConfigOption::Dr(3)).unwrap();
+        transaction.stage(ConfigOption::Adr(false)).unwrap();
+        transaction.stage(ConfigOption::PwrLevel(1)).unwrap();
+        assert!(matches!(
+            driver.commit(&transaction),
+            Err(DriverError::UnexpectedResponse)
+        ));
+        module.done();
+    }
+
+    #[test]
+    fn commit_reloads_options_not_restored() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        let mut reply = String::<consts::U128>::from("OK\r\n");
+        reply.push_str(mock::WELCOME).unwrap();
+        module
+            .expect("at+get_config=dr", "OK5\r\n")
+            .expect("at+get_config=adr", "OKon\r\n")
+            .expect("at+set_config=dr:3", "OK\r\n")
+            .expect("at+set_config=adr:off", "ERROR-1\r\n")
+            .expect("at+set_config=dr:5", "ERROR-1\r\n")
+            .expect("at+reset=1", &reply);
+        let mut transaction = ConfigTransaction::new();
+        transaction.stage(ConfigOption::Dr(3)).unwrap();
+        transaction.stage(ConfigOption::Adr(false)).unwrap();
+        assert!(matches!(
+            driver.commit(&transaction),
+            Err(DriverError::UnexpectedResponse)
+        ));
+        module.done();
+    }
+
+    #[test]
+    fn commit_reports_failed_reload() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=dr", "ERROR-1\r\n")
+            .expect("at+get_config=pwr_level", "ERROR-1\r\n")
+            .expect("at+set_config=dr:3", "OK\r\n")
+            .expect("at+set_config=pwr_level:1", "ERROR-1\r\n")
+            .expect("at+reset=1", "ERROR-1\r\n");
+        let mut transaction = ConfigTransaction::new();
+        transaction.stage(ConfigOption::Dr(3)).unwrap();
+        transaction.stage(ConfigOption::PwrLevel(1)).unwrap();
+        assert!(matches!(
+            driver.commit(&transaction),
+            Err(DriverError::RollbackFailed)
+        ));
+        module.done();
+    }
+
+    #[test]
+    fn confirmed_uplink_outcomes() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=dr", "OK5\r\n")
+            .expect("at+send=1,1,12", "OK\r\nat+recv=1,0,0\r\n")
+            .expect("at+send=1,1,12", "OK\r\nat+recv=1,1,0\r\n")
+            .expect("at+send=1,1,12", "OK\r\nat+recv=5,0,0\r\n")
+            .expect("at+send=1,1,12", "OK\r\nat+recv=6,0,0\r\n");
+        let acked = [true, false, false, false];
+        for acked in acked.iter() {
+            let result = driver.send(QoS::Confirmed, 1, &[0x12]).unwrap();
+            assert_eq!(*acked, result.acked);
+            assert_eq!(None, result.transmissions);
+            assert!(result.downlink.is_none());
+        }
+        module.done();
+    }
+
+    #[test]
+    fn uplink_status_counts_transmissions() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        driver.set_uplink_status(true);
+        module
+            .expect("at+get_config=dr", "OK5\r\n")
+            .expect("at+status", "OK3,1,2,1,0,-90,5\r\n")
+            .expect("at+send=1,1,12", "OK\r\nat+recv=1,0,0\r\n")
+            .expect("at+status", "OK4,3,3,1,0,-112,-7\r\n");
+        let result = driver.send(QoS::Confirmed, 1, &[0x12]).unwrap();
+        module.done();
+        assert!(result.acked);
+        assert_eq!(Some(3), result.transmissions);
+        assert_eq!(Some(-112), result.rssi);
+        assert_eq!(Some(-7), result.snr);
+    }
+
+    #[test]
+    fn uplink_outcome_is_kept_if_status_fails() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        driver.set_uplink_status(true);
+        module
+            .expect("at+get_config=dr", "OK5\r\n")
+            .expect(
+                "at+send=0,1,12",
+                "OK\r\nat+recv=0,2,2ab\r\nat+recv=2,0,0\r\n",
+            )
+            .fail("at+status");
+        let result = driver.send(QoS::Unconfirmed, 1, &[0x12]).unwrap();
+        module.done();
+        assert_eq!(None, result.rssi);
+        let downlink = result.downlink.unwrap();
+        assert_eq!(Some(&b"ab"[..]), driver.payload(&downlink.data));
+    }
+
+    #[test]
+    fn poll_downlinks_stops_at_limit() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=dr", "OK5\r\n")
+            .expect(
+                "at+send=0,2,00",
+                "OK\r\nat+recv=0,2,2ab\r\nat+recv=2,0,0\r\n",
+            )
+            .expect(
+                "at+send=0,2,00",
+                "OK\r\nat+recv=0,2,2cd\r\nat+recv=2,0,0\r\n",
+            );
+        assert_eq!(2, driver.poll_downlinks(2, 2).unwrap());
+        module.done();
+
+        let mut buf = [0; 2];
+        for expected in [b"ab", b"cd"].iter() {
+            assert_eq!(2, driver.try_recv(2, &mut buf).unwrap());
+            assert_eq!(*expected, &buf);
+        }
+
+        module
+            .expect(
+                "at+send=0,2,00",
+                "OK\r\nat+recv=0,2,2ef\r\nat+recv=2,0,0\r\n",
+            )
+            .expect("at+send=0,2,00", "OK\r\nat+recv=2,0,0\r\n");
+        assert_eq!(1, driver.poll_downlinks(2, 5).unwrap());
+        module.done();
+        assert_eq!(2, driver.try_recv(2, &mut buf).unwrap());
+        assert_eq!(b"ef", &buf);
+    }
+
+    #[test]
+    fn poll_downlinks_stops_when_queue_is_full() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=dr", "OK5\r\n")
+            .expect(
+                "at+send=0,2,00",
+                "OK\r\nat+recv=0,2,2ab\r\nat+recv=2,0,0\r\n",
+            )
+            .expect(
+                "at+send=0,2,00",
+                "OK\r\nat+recv=0,2,2cd\r\nat+recv=2,0,0\r\n",
+            )
+            .expect(
+                "at+send=0,2,00",
+                "OK\r\nat+recv=0,2,2ef\r\nat+recv=2,0,0\r\n",
+            );
+        assert_eq!(3, driver.poll_downlinks(2, 5).unwrap());
+        module.done();
+
+        let mut buf = [0; 2];
+        for expected in [b"ab", b"cd", b"ef"].iter() {
+            assert_eq!(2, driver.try_recv(2, &mut buf).unwrap());
+            assert_eq!(*expected, &buf);
+        }
+    }
+
+    #[test]
+    fn events_nobody_awaits_are_dropped() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        // Wake up, stale uplink and join outcomes and an unknown event, more than the queue holds
+        module.feed("at+recv=8,0,0\r\nat+recv=1,0,0\r\nat+recv=3,0,0\r\nat+recv=42,0,0\r\n");
+        module.feed("at+recv=0,2,2ab\r\n");
+        for _ in 0..5 {
+            driver.process().unwrap();
+            driver.digest().unwrap();
+        }
+        let mut buf = [0; 4];
+        assert_eq!(2, driver.try_recv(2, &mut buf).unwrap());
+        assert_eq!(b"ab", &buf[..2]);
+    }
+
+    #[test]
+    fn suppressed_downlinks_are_not_counted() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        driver.enable_metrics(|| 0);
+        module.feed("at+recv=0,2,2ab\r\nat+recv=7,2,2ab\r\n");
+        for _ in 0..2 {
+            driver.process().unwrap();
+            driver.digest().unwrap();
+        }
+        let metrics = driver.metrics().unwrap();
+        assert_eq!(1, metrics.downlinks);
+        assert_eq!(1, metrics.repeated_downlinks);
+    }
+
+    #[test]
+    fn class_c_downlink_without_uplink() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module
+            .expect("at+get_config=class", "OK0\r\n")
+            .expect("at+set_config=class:2", "OK\r\n");
+        driver.set_class(DeviceClass::C).unwrap();
+        module.done();
+
+        let mut buf = [0; 4];
+        assert_eq!(0, driver.try_recv(3, &mut buf).unwrap());
+        module.feed("at+recv=0,3,2ab\r\n");
+        assert_eq!(2, driver.try_recv(3, &mut buf).unwrap());
+        assert_eq!(b"ab", &buf[..2]);
+    }
+
+    #[test]
+    fn reset_discards_queued_downlinks() {
+        let module = Module::new();
+        let mut driver = module.driver().unwrap();
+        module.feed("at+recv=0,2,2ab\r\n");
+        driver.process().unwrap();
+        driver.digest().unwrap();
+        let downlink = driver.next_downlink().unwrap().unwrap();
+        module.feed("at+recv=0,2,2cd\r\n");
+        driver.process().unwrap();
+        driver.digest().unwrap();
+
+        let mut reply = String::<consts::U128>::from("OK\r\n");
+        reply.push_str(mock::WELCOME).unwrap();
+        module.expect("at+reset=0", &reply);
+        driver.reset(ResetMode::Restart).unwrap();
+        module.done();
+        assert_eq!(None, driver.payload(&downlink.data));
+        assert!(driver.next_downlink().unwrap().is_none());
+    }
 }
@@ -0,0 +1,220 @@
+use crate::{Command, EventCode, Response};
+use heapless::{consts, Vec};
+
+/// Type of command sent to the module, used to group metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandKind {
+    QueryFirmwareInfo,
+    SetBand,
+    SetMode,
+    GetBand,
+    Reset,
+    Join,
+    SetConfig,
+    GetConfig,
+    Send,
+    GetStatus,
+    Raw,
+}
+
+const COMMAND_KINDS: usize = 11;
+
+impl<'a> Command<'a> {
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Command::QueryFirmwareInfo => CommandKind::QueryFirmwareInfo,
+            Command::SetBand(_) => CommandKind::SetBand,
+            Command::SetMode(_) => CommandKind::SetMode,
+            Command::GetBand => CommandKind::GetBand,
+            Command::Reset(_) => CommandKind::Reset,
+            Command::Join(_) => CommandKind::Join,
+            Command::SetConfig(_) | Command::SetConfigValue(_, _) => CommandKind::SetConfig,
+            Command::GetConfig(_) => CommandKind::GetConfig,
+            Command::Send(_, _, _) => CommandKind::Send,
+            Command::GetStatus => CommandKind::GetStatus,
+            Command::Raw(_) => CommandKind::Raw,
+        }
+    }
+}
+
+/// Time from sending a command until the module responds, in milliseconds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Latency {
+    pub min: u32,
+    pub max: u32,
+    total: u64,
+    count: u32,
+}
+
+impl Latency {
+    fn record(&mut self, latency: u32) {
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        if latency > self.max {
+            self.max = latency;
+        }
+        self.total = self.total.saturating_add(latency as u64);
+        self.count = self.count.saturating_add(1);
+    }
+
+    pub fn avg(&self) -> u32 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.total / self.count as u64) as u32
+        }
+    }
+}
+
+/// Counters kept by the driver once enabled using `Rak811Driver::enable_metrics`. Counters wrap
+/// around on overflow.
+#[derive(Debug, Clone)]
+pub struct DriverMetrics {
+    clock: fn() -> u32,
+    commands: [u32; COMMAND_KINDS],
+    latency: [Latency; COMMAND_KINDS],
+    errors: Vec<(i8, u32), consts::U8>,
+    other_errors: u32,
+    pub timeouts: u32,
+    pub bytes_sent: u32,
+    /// Downlinks queued for the application. Repeated downlinks are only counted if delivered.
+    pub downlinks: u32,
+    /// Downlinks reported as repeated by the module, whether suppressed or not.
+    pub repeated_downlinks: u32,
+    pub overflows: u32,
+}
+
+impl DriverMetrics {
+    /// Create metrics using the given clock returning the current time in milliseconds.
+    pub fn new(clock: fn() -> u32) -> Self {
+        DriverMetrics {
+            clock,
+            commands: [0; COMMAND_KINDS],
+            latency: [Latency::default(); COMMAND_KINDS],
+            errors: Vec::new(),
+            other_errors: 0,
+            timeouts: 0,
+            bytes_sent: 0,
+            downlinks: 0,
+            repeated_downlinks: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Clear all counters.
+    pub fn reset(&mut self) {
+        *self = DriverMetrics::new(self.clock);
+    }
+
+    pub fn commands_sent(&self, kind: CommandKind) -> u32 {
+        self.commands[kind as usize]
+    }
+
+    /// Number of commands sent, for all command types.
+    pub fn total_commands(&self) -> u32 {
+        self.commands.iter().fold(0, |sum, n| sum.wrapping_add(*n))
+    }
+
+    pub fn latency(&self, kind: CommandKind) -> Latency {
+        self.latency[kind as usize]
+    }
+
+    /// Number of error responses with the given code.
+    pub fn errors(&self, code: i8) -> u32 {
+        self.errors
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, n)| *n)
+            .unwrap_or(0)
+    }
+
+    /// Number of error responses, for all codes.
+    pub fn total_errors(&self) -> u32 {
+        self.errors
+            .iter()
+            .fold(self.other_errors, |sum, (_, n)| sum.wrapping_add(*n))
+    }
+
+    pub(crate) fn now(&self) -> u32 {
+        (self.clock)()
+    }
+
+    pub(crate) fn command_sent(&mut self, kind: CommandKind, len: usize) {
+        self.commands[kind as usize] = self.commands[kind as usize].wrapping_add(1);
+        self.bytes_sent = self.bytes_sent.wrapping_add(len as u32);
+    }
+
+    pub(crate) fn command_completed(&mut self, kind: CommandKind, start: u32, response: &Response) {
+        let latency = self.now().wrapping_sub(start);
+        self.latency[kind as usize].record(latency);
+        if let Response::Error(code) = response {
+            match self.errors.iter_mut().find(|(c, _)| c == code) {
+                Some((_, n)) => *n = n.wrapping_add(1),
+                None => {
+                    if self.errors.push((*code, 1)).is_err() {
+                        self.other_errors = self.other_errors.wrapping_add(1);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn downlink_queued(&mut self) {
+        self.downlinks = self.downlinks.wrapping_add(1);
+    }
+
+    pub(crate) fn response_received(&mut self, response: &Response) {
+        match response {
+            Response::Recv(EventCode::DownlinkRepeated, _, _, _) => {
+                self.repeated_downlinks = self.repeated_downlinks.wrapping_add(1)
+            }
+            Response::Recv(EventCode::TxTimeout, _, _, _)
+            | Response::Recv(EventCode::Rx2Timeout, _, _, _) => {
+                self.timeouts = self.timeouts.wrapping_add(1)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_min_avg_max() {
+        let mut latency = Latency::default();
+        latency.record(10);
+        latency.record(30);
+        latency.record(20);
+        assert_eq!(10, latency.min);
+        assert_eq!(30, latency.max);
+        assert_eq!(20, latency.avg());
+    }
+
+    #[test]
+    fn counters_wrap_around() {
+        let mut metrics = DriverMetrics::new(|| 0);
+        metrics.downlinks = u32::MAX;
+        metrics.downlink_queued();
+        assert_eq!(0, metrics.downlinks);
+        metrics.command_sent(CommandKind::Send, 10);
+        metrics.commands[CommandKind::Join as usize] = u32::MAX;
+        assert_eq!(0, metrics.total_commands());
+    }
+
+    #[test]
+    fn errors_by_code() {
+        let mut metrics = DriverMetrics::new(|| 0);
+        metrics.command_completed(CommandKind::Join, 0, &Response::Error(-1));
+        metrics.command_completed(CommandKind::Send, 0, &Response::Error(-1));
+        metrics.command_completed(CommandKind::Send, 0, &Response::Error(2));
+        assert_eq!(2, metrics.errors(-1));
+        assert_eq!(1, metrics.errors(2));
+        assert_eq!(3, metrics.total_errors());
+        metrics.reset();
+        assert_eq!(0, metrics.total_errors());
+    }
+}
@@ -0,0 +1,140 @@
+// Scripted RAK811 module for driver tests.
+extern crate std;
+
+use crate::{DriverError, Rak811Driver};
+use core::convert::Infallible;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::serial::{Read, Write};
+use heapless::ArrayLength;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::string::String;
+use std::vec::Vec;
+
+pub const WELCOME: &str = "Welcome to RAK811\r\n\r\nSelected LoraWAN 2.0.3 Region: EU868 \r\n\r\n";
+
+// Reads without data before the module is considered stuck.
+const MAX_IDLE_READS: u32 = 1_000_000;
+
+#[derive(Default)]
+struct State {
+    rx: VecDeque<u8>,
+    line: Vec<u8>,
+    // Commands with their reply, or `None` if writing the command fails
+    script: VecDeque<(&'static str, Option<String>)>,
+    idle_reads: u32,
+}
+
+/// Module answering each command with the next scripted reply.
+#[derive(Clone, Default)]
+pub struct Module(Rc<RefCell<State>>);
+
+pub struct Tx(Module);
+pub struct Rx(Module);
+pub struct Pin;
+
+pub type MockDriver = Rak811Driver<Tx, Rx, Pin>;
+
+impl Module {
+    /// Create a module which sends the welcome banner after reset.
+    pub fn new() -> Self {
+        let module = Module::default();
+        module.feed(WELCOME);
+        module
+    }
+
+    /// Expect the next command to be `command`, and answer it with `reply`.
+    pub fn expect(&self, command: &'static str, reply: &str) -> &Self {
+        self.0
+            .borrow_mut()
+            .script
+            .push_back((command, Some(reply.into())));
+        self
+    }
+
+    /// Expect the next command to be `command`, and fail writing it.
+    pub fn fail(&self, command: &'static str) -> &Self {
+        self.0.borrow_mut().script.push_back((command, None));
+        self
+    }
+
+    /// Send data without a command, such as an event.
+    pub fn feed(&self, data: &str) {
+        self.0.borrow_mut().rx.extend(data.bytes());
+    }
+
+    /// Check that all scripted commands were sent.
+    pub fn done(&self) {
+        assert!(self.0.borrow().script.is_empty(), "commands not sent");
+    }
+
+    pub fn driver(&self) -> Result<MockDriver, DriverError> {
+        Rak811Driver::new(Tx(self.clone()), Rx(self.clone()), Pin)
+    }
+
+    /// Create a driver with a parse buffer of `BUF` bytes.
+    pub fn driver_with_buffer<BUF: ArrayLength<u8>>(
+        &self,
+    ) -> Result<Rak811Driver<Tx, Rx, Pin, BUF>, DriverError> {
+        Rak811Driver::new(Tx(self.clone()), Rx(self.clone()), Pin)
+    }
+}
+
+impl Write<u8> for Tx {
+    type Error = ();
+
+    fn write(&mut self, word: u8) -> nb::Result<(), ()> {
+        let mut state = (self.0).0.borrow_mut();
+        state.line.push(word);
+        if state.line.ends_with(b"\r\n") {
+            let len = state.line.len() - 2;
+            let command = String::from_utf8(state.line.drain(..).take(len).collect()).unwrap();
+            let (expected, reply) = state
+                .script
+                .pop_front()
+                .unwrap_or_else(|| panic!("unexpected command {}", command));
+            assert_eq!(expected, command);
+            match reply {
+                Some(reply) => state.rx.extend(reply.bytes()),
+                None => return Err(nb::Error::Other(())),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl Read<u8> for Rx {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        let mut state = (self.0).0.borrow_mut();
+        match state.rx.pop_front() {
+            Some(b) => {
+                state.idle_reads = 0;
+                Ok(b)
+            }
+            None => {
+                state.idle_reads += 1;
+                assert!(state.idle_reads < MAX_IDLE_READS, "no response from module");
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+}
+
+impl OutputPin for Pin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
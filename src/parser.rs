@@ -2,13 +2,17 @@ use nom::alt;
 use nom::char;
 use nom::character::streaming::digit1;
 use nom::do_parse;
+use nom::error::ErrorKind;
 use nom::named;
 use nom::opt;
 use nom::tag;
 use nom::take;
+use nom::take_until;
 use nom::IResult;
 
 use super::{protocol::Decoder, EventCode, FirmwareInfo, LoraRegion, Response};
+use core::convert::TryFrom;
+use heapless::{ArrayLength, String};
 
 fn ascii_to_digit(character: u8) -> Option<u8> {
     match character {
@@ -28,40 +32,55 @@ fn ascii_to_digit(character: u8) -> Option<u8> {
 
 fn atoi_u32(digits: &[u8]) -> Option<u32> {
     let mut num: u32 = 0;
-    let len = digits.len();
-    for (i, digit) in digits.iter().enumerate() {
-        let digit = ascii_to_digit(*digit as u8)? as u32;
-        let mut exp: u32 = 1;
-        for _ in 0..(len - i - 1) {
-            exp *= 10;
-        }
-        num += exp * digit;
+    for digit in digits.iter() {
+        let digit = ascii_to_digit(*digit)? as u32;
+        num = num.checked_mul(10)?.checked_add(digit)?;
     }
     Some(num)
 }
 
 fn atoi_u8(digits: &[u8]) -> Option<u8> {
     let mut num: u8 = 0;
-    let len = digits.len();
-    for (i, digit) in digits.iter().enumerate() {
+    for digit in digits.iter() {
         let digit = ascii_to_digit(*digit)?;
-        let mut exp = 1;
-        for _ in 0..(len - i - 1) {
-            exp *= 10;
-        }
-        num += exp * digit;
+        num = num.checked_mul(10)?.checked_add(digit)?;
     }
     Some(num)
 }
 
 fn parse_u8(input: &[u8]) -> IResult<&[u8], u8> {
-    let (input, digits) = digit1(input)?;
-    IResult::Ok((input, atoi_u8(digits).unwrap()))
+    let (remainder, digits) = digit1(input)?;
+    match atoi_u8(digits) {
+        Some(num) => IResult::Ok((remainder, num)),
+        None => IResult::Err(nom::Err::Error((input, ErrorKind::Digit))),
+    }
 }
 
-fn parse_u32(input: &[u8]) -> IResult<&[u8], u32> {
-    let (input, digits) = digit1(input)?;
-    IResult::Ok((input, atoi_u32(digits).unwrap()))
+fn parse_i8(input: &[u8]) -> IResult<&[u8], i8> {
+    let (digits, negative) = match input.first() {
+        Some(b'-') => (&input[1..], true),
+        _ => (input, false),
+    };
+    let (remainder, digits) = digit1(digits)?;
+    let num = atoi_u32(digits)
+        .and_then(|num| i32::try_from(num).ok())
+        .map(|num| if negative { -num } else { num })
+        .and_then(|num| i8::try_from(num).ok());
+    match num {
+        Some(num) => IResult::Ok((remainder, num)),
+        None => IResult::Err(nom::Err::Error((input, ErrorKind::Digit))),
+    }
+}
+
+// Copy the value into a string, truncating it if too long.
+fn to_string<N: ArrayLength<u8>>(value: &[u8]) -> String<N> {
+    let mut s = String::new();
+    for b in value.iter() {
+        if s.push(*b as char).is_err() {
+            break;
+        }
+    }
+    s
 }
 
 #[rustfmt::skip]
@@ -195,7 +214,7 @@ named!(
         rssi_sign: opt!(char!('-')) >>
         rssi: parse_u8 >>
         char!(',') >>
-        snr: parse_u32 >>
+        snr: parse_i8 >>
         crlf >>
         ( {
             Response::Status {
@@ -236,9 +255,10 @@ named!(
     )
 );
 
+// Parse a receive event, returning the event code, port and received data.
 #[rustfmt::skip]
 named!(
-    pub recv<Response>,
+    pub recv<(EventCode, u8, &[u8])>,
     do_parse!(
         tag!("at+recv=") >>
         status: parse_u8 >>
@@ -248,22 +268,76 @@ named!(
         len: parse_u8 >>
         data: take!(len) >>
         crlf >>
-        ( {
-            let rx = if len > 0 {
-                let mut buf: [u8; crate::RECV_BUFFER_LEN] = [0; crate::RECV_BUFFER_LEN];
-                for (i, b) in data.iter().enumerate() {
-                    buf[i] = *b;
-                }
-                Some(buf)
-            } else {
-                None
-            };
-            Response::Recv(EventCode::parse(status), port, len as usize, rx)
-          }
+        (
+            (EventCode::parse(status), port, data)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub config_value<Response>,
+    do_parse!(
+        tag!("OK") >>
+        value: take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::Config(to_string(value))
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub line<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        opt!(crlf) >>
+        value: take_until!("\r\n") >>
+        crlf >>
+        (
+            Response::Line(to_string(value))
         )
     )
 );
 
+#[rustfmt::skip]
+named!(
+    text_line,
+    do_parse!(
+        value: take_until!("\r\n") >>
+        crlf >>
+        (value)
+    )
+);
+
+/// Frame the lines of a response up to the terminating `OK` or `ERROR`, passing the content to `out`.
+/// Content following `OK` on the last line is kept, lines are separated by `\n`, and empty lines
+/// are skipped. The result is the error code if the response is terminated by `ERROR`.
+pub fn lines<'i>(input: &'i [u8], out: &mut dyn FnMut(&[u8])) -> IResult<&'i [u8], Result<(), i8>> {
+    let mut first = true;
+    let mut rest = input;
+    loop {
+        if let Ok((remainder, Response::Error(code))) = error(rest) {
+            return Ok((remainder, Err(code)));
+        }
+        let (remainder, line) = text_line(rest)?;
+        rest = remainder;
+        let last = line.starts_with(b"OK");
+        let line = if last { &line[2..] } else { line };
+        if !line.is_empty() {
+            if !first {
+                out(b"\n");
+            }
+            out(line);
+            first = false;
+        }
+        if last {
+            return Ok((rest, Ok(())));
+        }
+    }
+}
+
 named!(
     pub parse<Response>,
     alt!(
@@ -272,16 +346,101 @@ named!(
         | firmware_info
         | lora_band
         | mode_info
-        | recv
         | status
         | welcome
+        | config_value
     )
 );
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn parse_config_value() {
+        let (remainder, response) = parse(b"OK26011bda\r\n").unwrap();
+        assert!(remainder.is_empty());
+        match response {
+            Response::Config(value) => assert_eq!("26011bda", value.as_str()),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_raw_lines() {
+        let (remainder, response) = line(b"\r\nOK2.0.3.0\r\n").unwrap();
+        assert!(remainder.is_empty());
+        assert!(matches!(response, Response::Line(line) if line.as_str() == "OK2.0.3.0"));
+    }
+
+    #[test]
+    fn parse_recv() {
+        assert!(matches!(
+            recv(b"at+recv=2,0,0\r\n"),
+            Ok((_, (EventCode::TxUnconfirmed, 0, data))) if data.is_empty()
+        ));
+        assert!(matches!(
+            recv(b"at+recv=0,1,2ab\r\n"),
+            Ok((_, (EventCode::RecvData, 1, data))) if data == b"ab"
+        ));
+    }
+
+    #[test]
+    fn parse_multiple_lines() {
+        let mut content: heapless::Vec<u8, heapless::consts::U64> = heapless::Vec::new();
+        let mut out = |b: &[u8]| content.extend_from_slice(b).unwrap();
+        assert!(matches!(
+            lines(b"\r\nat+help\r\nOK", &mut out),
+            Err(nom::Err::Incomplete(_))
+        ));
+        content.clear();
+        let mut out = |b: &[u8]| content.extend_from_slice(b).unwrap();
+        let (remainder, result) = lines(b"\r\nline 1\r\nline 2\r\nOK\r\nOK", &mut out).unwrap();
+        assert_eq!(b"OK", remainder);
+        assert_eq!(Ok(()), result);
+        assert_eq!(b"line 1\nline 2", &content[..]);
+
+        content.clear();
+        let mut out = |b: &[u8]| content.extend_from_slice(b).unwrap();
+        let (_, result) = lines(b"OK*0,on,868100000,0,5;\r\n", &mut out).unwrap();
+        assert_eq!(Ok(()), result);
+        assert_eq!(b"*0,on,868100000,0,5;", &content[..]);
+        assert!(matches!(
+            lines(b"ERROR-3\r\n", &mut |_| {}),
+            Ok((_, Err(-3)))
+        ));
+    }
+
+    #[test]
+    fn parse_status_before_config_value() {
+        let (_, response) = parse(b"OK1,0,1,0,0,-47,7\r\n").unwrap();
+        assert!(matches!(
+            response,
+            Response::Status {
+                tx_ok: 1,
+                rssi: -47,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn parse_negative_snr() {
+        let (_, response) = parse(b"OK3,1,2,1,0,-112,-7\r\n").unwrap();
+        assert!(matches!(
+            response,
+            Response::Status {
+                rssi: -112,
+                snr: -7,
+                ..
+            }
+        ));
+        assert!(parse_i8(b"-129,").is_err());
+        assert!(matches!(parse_i8(b"12,"), Ok((_, 12))));
+    }
 }
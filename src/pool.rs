@@ -0,0 +1,165 @@
+use heapless::{consts, ArrayLength, Vec};
+
+/// Reference to data stored in the payload pool of the driver, read using
+/// `Rak811Driver::payload` and released using `Rak811Driver::release`.
+///
+/// Handles are numbered with a 32-bit counter which wraps around. A handle kept after its data
+/// was released or evicted reads `None`, unless 2^32 payloads have been stored meanwhile and the
+/// number is reused, so handles should not be kept for long after use.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PayloadHandle {
+    id: u32,
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: u32,
+    offset: usize,
+    len: usize,
+}
+
+/// Ring of `N` bytes holding received payloads and multi-line responses. When full,
+/// the oldest data is evicted, and reading it through its handle returns `None`.
+pub struct PayloadPool<N: ArrayLength<u8>> {
+    buffer: Vec<u8, N>,
+    entries: Vec<Entry, consts::U8>,
+    head: usize,
+    next_id: u32,
+    evicted: usize,
+}
+
+impl<N: ArrayLength<u8>> Default for PayloadPool<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<N: ArrayLength<u8>> PayloadPool<N> {
+    pub fn new() -> Self {
+        let mut buffer = Vec::new();
+        buffer.resize_default(buffer.capacity()).ok();
+        PayloadPool {
+            buffer,
+            entries: Vec::new(),
+            head: 0,
+            next_id: 0,
+            evicted: 0,
+        }
+    }
+
+    /// Store `len` bytes, written using `get_mut`. Returns `None` if `len` exceeds the pool size.
+    pub fn alloc(&mut self, len: usize) -> Option<PayloadHandle> {
+        if len > self.buffer.len() {
+            return None;
+        }
+        if self.head + len > self.buffer.len() {
+            self.head = 0;
+        }
+        let (start, end) = (self.head, self.head + len);
+        let before = self.entries.len();
+        self.retain(|e| e.offset + e.len <= start || e.offset >= end || e.len == 0);
+        if self.entries.len() == self.entries.capacity() {
+            self.remove(0);
+        }
+        // Skip numbers still in use after wrapping around
+        let mut id = self.next_id;
+        while self.entries.iter().any(|e| e.id == id) {
+            id = id.wrapping_add(1);
+        }
+        self.next_id = id.wrapping_add(1);
+        self.entries
+            .push(Entry {
+                id,
+                offset: start,
+                len,
+            })
+            .ok();
+        self.head = end;
+        self.evicted += before + 1 - self.entries.len();
+        Some(PayloadHandle { id })
+    }
+
+    /// Number of unreleased entries evicted to make room since last called.
+    pub fn take_evicted(&mut self) -> usize {
+        core::mem::replace(&mut self.evicted, 0)
+    }
+
+    pub fn get(&self, handle: &PayloadHandle) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|e| e.id == handle.id)
+            .map(|e| &self.buffer[e.offset..e.offset + e.len])
+    }
+
+    pub fn get_mut(&mut self, handle: &PayloadHandle) -> Option<&mut [u8]> {
+        let entry = self.entries.iter().find(|e| e.id == handle.id)?;
+        Some(&mut self.buffer[entry.offset..entry.offset + entry.len])
+    }
+
+    pub fn release(&mut self, handle: PayloadHandle) {
+        self.retain(|e| e.id != handle.id);
+    }
+
+    /// Release all entries. Numbering continues, so handles to the released data read `None`.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.head = 0;
+    }
+
+    // Remove the entry at the index, keeping the order of the remaining entries.
+    fn remove(&mut self, index: usize) {
+        for i in index..self.entries.len() - 1 {
+            self.entries.swap(i, i + 1);
+        }
+        self.entries.pop();
+    }
+
+    fn retain<F: Fn(&Entry) -> bool>(&mut self, f: F) {
+        let mut i = 0;
+        while i < self.entries.len() {
+            if f(&self.entries[i]) {
+                i += 1;
+            } else {
+                self.remove(i);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest() {
+        let mut pool: PayloadPool<consts::U8> = PayloadPool::new();
+        let first = pool.alloc(4).unwrap();
+        pool.get_mut(&first).unwrap().copy_from_slice(b"abcd");
+        let second = pool.alloc(3).unwrap();
+        assert_eq!(0, pool.take_evicted());
+        assert_eq!(Some(&b"abcd"[..]), pool.get(&first));
+
+        // Wraps around, overwriting the first entry
+        let third = pool.alloc(2).unwrap();
+        assert_eq!(1, pool.take_evicted());
+        assert_eq!(None, pool.get(&first));
+        assert_eq!(2, pool.get(&third).unwrap().len());
+
+        pool.release(second);
+        assert!(pool.alloc(9).is_none());
+    }
+
+    #[test]
+    fn ids_in_use_are_not_reused() {
+        let mut pool: PayloadPool<consts::U8> = PayloadPool::new();
+        pool.next_id = u32::MAX;
+        let last = pool.alloc(1).unwrap();
+        let first = pool.alloc(1).unwrap();
+        pool.next_id = u32::MAX;
+        let next = pool.alloc(1).unwrap();
+        assert_ne!(last, next);
+        assert_ne!(first, next);
+        assert_eq!(1, next.id);
+    }
+}
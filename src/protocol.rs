@@ -1,8 +1,11 @@
-use core::fmt::Write;
+use crate::{Channel, DriverError, PayloadHandle};
+use core::fmt::{self, Debug, Formatter, Write};
 use drogue_lora::*;
-use heapless::{consts, String};
+use heapless::{consts, ArrayLength, String, Vec};
+use zeroize::Zeroize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ConfigKey {
     DevAddr,
     DevEui,
@@ -12,9 +15,22 @@ pub enum ConfigKey {
     AppsKey,
     ChMask,
     ChList,
+    Dr,
+    Adr,
+    PwrLevel,
+    Class,
+}
+
+/// LoRaWAN device class. Class B is not supported by the module.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceClass {
+    /// Receive only in the windows following an uplink.
+    A,
+    /// Receive continuously when not transmitting.
+    C,
 }
 
-#[derive(Debug)]
 pub enum Command<'a> {
     QueryFirmwareInfo,
     SetBand(LoraRegion),
@@ -23,12 +39,17 @@ pub enum Command<'a> {
     Reset(ResetMode),
     Join(ConnectMode),
     SetConfig(ConfigOption<'a>),
+    /// Store a configuration value previously read from the module.
+    SetConfigValue(ConfigKey, &'a str),
     GetConfig(ConfigKey),
     Send(QoS, Port, &'a [u8]),
     GetStatus,
+    /// Command not modelled by the driver, sent as is.
+    Raw(&'a str),
 }
 
-#[derive(Debug)]
+/// Configuration option to store in the module. Key material is redacted from the `Debug` output.
+#[derive(Clone, Copy)]
 pub enum ConfigOption<'a> {
     DevAddr(&'a DevAddr),
     DevEui(&'a EUI),
@@ -37,19 +58,20 @@ pub enum ConfigOption<'a> {
     NwksKey(&'a NwksKey),
     AppsKey(&'a AppsKey),
     ChMask(u8, u16),
+    Dr(u8),
+    Adr(bool),
+    PwrLevel(u8),
+    /// Add, replace or disable the channel with the index of the entry.
+    Channel(Channel),
+    Class(DeviceClass),
     /*
-    PwrLevel,
-    Adr,
-    Dr,
     PublicNet,
     RxDelay1,
     Rx2,
-    ChList,
     ChMask,
     MaxChs,
     JoinCnt,
     Nbtrans,
-    Class,
     Duty,*/
 }
 
@@ -61,7 +83,16 @@ pub enum Response {
     Error(i8),
     FirmwareInfo(FirmwareInfo),
     LoraBand(LoraRegion),
-    Recv(EventCode, Port, usize, Option<[u8; crate::RECV_BUFFER_LEN]>),
+    Config(ConfigValue),
+    /// Line of a response to a raw command.
+    Line(RawLine),
+    /// Content of a response spanning several lines, with lines separated by `\n`,
+    /// stored in the payload pool.
+    Lines(PayloadHandle),
+    /// Response produced by a custom parser, tagged with an application defined identifier.
+    Custom(u8, CustomData),
+    /// Event reported by the module. Received data is stored in the payload pool.
+    Recv(EventCode, Port, usize, Option<PayloadHandle>),
     Status {
         tx_ok: u8,
         tx_err: u8,
@@ -69,12 +100,13 @@ pub enum Response {
         rx_timeout: u8,
         rx_err: u8,
         rssi: i8,
-        snr: u32,
+        snr: i8,
     },
     Initialized(LoraRegion),
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum EventCode {
     RecvData,
@@ -90,8 +122,49 @@ pub enum EventCode {
     Unknown,
 }
 
+/// Outcome of an uplink, returned by `Rak811Driver::send` and `Rak811Driver::poll_send`.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UplinkResult {
+    /// True if the uplink was confirmed and acknowledged by the network.
+    pub acked: bool,
+    /// Number of transmissions, including retransmissions, counted by the module. Only
+    /// reported for confirmed uplinks, and if enabled using `Rak811Driver::set_uplink_status`.
+    pub transmissions: Option<u8>,
+    /// Signal strength in dBm of the acknowledgement or downlink received for the uplink, if
+    /// enabled using `Rak811Driver::set_uplink_status`.
+    pub rssi: Option<i8>,
+    /// Signal to noise ratio in dB of the acknowledgement or downlink received for the uplink,
+    /// if enabled using `Rak811Driver::set_uplink_status`.
+    pub snr: Option<i8>,
+    /// Downlink received in the receive windows of the uplink.
+    pub downlink: Option<Downlink>,
+}
+
+/// Downlink data stored in the payload pool, read using `Rak811Driver::payload` and released
+/// using `Rak811Driver::release`.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Downlink {
+    pub port: Port,
+    pub data: PayloadHandle,
+    /// True if the module reported the downlink as a repetition of the previous one.
+    pub repeated: bool,
+}
+
+/// Handling of downlinks reported by the module with `EventCode::DownlinkRepeated`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RepeatedDownlinks {
+    /// Drop repeated downlinks.
+    Suppress,
+    /// Deliver repeated downlinks like other downlinks, marked as repeated in `Downlink`.
+    Deliver,
+}
+
 /// Version information for the RAK811 board
 #[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FirmwareInfo {
     pub major: u8,
     pub minor: u8,
@@ -99,40 +172,92 @@ pub struct FirmwareInfo {
     pub build: u8,
 }
 
+/// Buffer for encoding commands, of the default size used by the driver.
 pub type CommandBuffer = String<consts::U128>;
 
+/// Configuration value as reported by the module.
+pub type ConfigValue = String<consts::U64>;
+
+/// Single line of a response, without line terminator. Longer lines are truncated.
+pub type RawLine = String<consts::U128>;
+
+/// Data of a response produced by a custom parser.
+pub type CustomData = Vec<u8, consts::U64>;
+
+/// Parser for responses not modelled by the driver, registered using `Rak811Driver::register_parser`.
+/// The parser must follow the nom streaming conventions: `nom::Err::Incomplete` if more data is
+/// needed, and `nom::Err::Error` if the input is not recognized.
+pub type ResponseParser = fn(&[u8]) -> nom::IResult<&[u8], Response>;
+
+/// Response to a command sent using `Rak811Driver::send_raw`.
+pub struct RawResponse<'b> {
+    data: &'b str,
+}
+
+impl<'b> RawResponse<'b> {
+    pub(crate) fn new(data: &'b str) -> Self {
+        RawResponse { data }
+    }
+}
+
+impl<'b> RawResponse<'b> {
+    /// The response lines, ending with the `OK` or `ERROR` line.
+    pub fn lines(&self) -> impl Iterator<Item = &'b str> {
+        self.data.split('\n')
+    }
+
+    /// True if the module accepted the command.
+    pub fn is_ok(&self) -> bool {
+        matches!(self.lines().last(), Some(line) if line.starts_with("OK"))
+    }
+
+    pub fn as_str(&self) -> &'b str {
+        self.data
+    }
+}
+
 impl<'a> Command<'a> {
     pub fn buffer() -> CommandBuffer {
         String::new()
     }
 
-    pub fn encode(&self, s: &mut CommandBuffer) {
+    /// Encode the command, failing if it does not fit the buffer.
+    pub fn encode<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> Result<(), DriverError> {
+        self.write_to(s).map_err(|_| DriverError::BufferTooSmall)
+    }
+
+    fn write_to<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result {
         match self {
             Command::QueryFirmwareInfo => {
-                write!(s, "at+version").unwrap();
+                write!(s, "at+version")?;
             }
             Command::SetBand(region) => {
-                write!(s, "at+band=").unwrap();
-                region.encode(s);
+                write!(s, "at+band=")?;
+                region.encode(s)?;
             }
             Command::GetBand => {
-                write!(s, "at+band").unwrap();
+                write!(s, "at+band")?;
             }
             Command::SetMode(mode) => {
-                write!(s, "at+mode=").unwrap();
-                mode.encode(s);
+                write!(s, "at+mode=")?;
+                mode.encode(s)?;
             }
             Command::Join(mode) => {
-                write!(s, "at+join=").unwrap();
-                mode.encode(s);
+                write!(s, "at+join=")?;
+                mode.encode(s)?;
             }
             Command::SetConfig(opt) => {
-                write!(s, "at+set_config=").unwrap();
-                opt.encode(s);
+                write!(s, "at+set_config=")?;
+                opt.encode(s)?;
+            }
+            Command::SetConfigValue(key, value) => {
+                write!(s, "at+set_config=")?;
+                key.encode(s)?;
+                write!(s, ":{}", value)?;
             }
             Command::GetConfig(key) => {
-                write!(s, "at+get_config=").unwrap();
-                key.encode(s);
+                write!(s, "at+get_config=")?;
+                key.encode(s)?;
             }
             Command::Reset(mode) => {
                 write!(
@@ -142,8 +267,7 @@ impl<'a> Command<'a> {
                         ResetMode::Restart => 0,
                         ResetMode::Reload => 1,
                     }
-                )
-                .unwrap();
+                )?;
             }
             Command::Send(qos, port, data) => {
                 write!(
@@ -155,88 +279,307 @@ impl<'a> Command<'a> {
                     },
                     port,
                     HexSlice(data),
-                )
-                .unwrap();
+                )?;
             }
             Command::GetStatus => {
-                write!(s, "at+status").unwrap();
+                write!(s, "at+status")?;
+            }
+            Command::Raw(command) => {
+                s.write_str(command)?;
             }
         }
+        Ok(())
     }
 }
 
+impl<'a> Command<'a> {
+    /// True if the response spans several lines, terminated by `OK` or `ERROR`.
+    pub fn is_multiline(&self) -> bool {
+        matches!(self, Command::GetConfig(ConfigKey::ChList))
+    }
+
+    /// True if the command or its response carries key material.
+    pub fn is_sensitive(&self) -> bool {
+        match self {
+            Command::SetConfig(opt) => opt.key().is_secret(),
+            Command::SetConfigValue(key, _) | Command::GetConfig(key) => key.is_secret(),
+            // Raw commands are not understood by the driver, so assume any mention of a key is secret.
+            Command::Raw(command) => command
+                .as_bytes()
+                .windows(3)
+                .any(|w| w.eq_ignore_ascii_case(b"key")),
+            _ => false,
+        }
+    }
+}
+
+impl<'a> Debug for Command<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::QueryFirmwareInfo => f.write_str("QueryFirmwareInfo"),
+            Command::SetBand(region) => f.debug_tuple("SetBand").field(region).finish(),
+            Command::SetMode(mode) => f.debug_tuple("SetMode").field(mode).finish(),
+            Command::GetBand => f.write_str("GetBand"),
+            Command::Reset(mode) => f.debug_tuple("Reset").field(mode).finish(),
+            Command::Join(mode) => f.debug_tuple("Join").field(mode).finish(),
+            Command::SetConfig(opt) => f.debug_tuple("SetConfig").field(opt).finish(),
+            Command::SetConfigValue(key, _) if key.is_secret() => f
+                .debug_tuple("SetConfigValue")
+                .field(key)
+                .field(&Redacted)
+                .finish(),
+            Command::SetConfigValue(key, value) => f
+                .debug_tuple("SetConfigValue")
+                .field(key)
+                .field(value)
+                .finish(),
+            Command::GetConfig(key) => f.debug_tuple("GetConfig").field(key).finish(),
+            Command::Send(qos, port, data) => f
+                .debug_tuple("Send")
+                .field(qos)
+                .field(port)
+                .field(data)
+                .finish(),
+            Command::GetStatus => f.write_str("GetStatus"),
+            Command::Raw(command) => f.debug_tuple("Raw").field(command).finish(),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a> defmt::Format for Command<'a> {
+    fn format(&self, f: defmt::Formatter) {
+        use defmt::{write, Debug2Format};
+        match self {
+            Command::QueryFirmwareInfo => write!(f, "QueryFirmwareInfo"),
+            Command::SetBand(region) => write!(f, "SetBand({})", Debug2Format(region)),
+            Command::SetMode(mode) => write!(f, "SetMode({})", Debug2Format(mode)),
+            Command::GetBand => write!(f, "GetBand"),
+            Command::Reset(mode) => write!(f, "Reset({})", Debug2Format(mode)),
+            Command::Join(mode) => write!(f, "Join({})", Debug2Format(mode)),
+            Command::SetConfig(opt) => write!(f, "SetConfig({})", opt),
+            Command::SetConfigValue(key, _) if key.is_secret() => {
+                write!(f, "SetConfigValue({}, <redacted>)", key)
+            }
+            Command::SetConfigValue(key, value) => write!(f, "SetConfigValue({}, {})", key, value),
+            Command::GetConfig(key) => write!(f, "GetConfig({})", key),
+            Command::Send(qos, port, data) => {
+                write!(f, "Send({}, {}, {})", Debug2Format(qos), port, data)
+            }
+            Command::GetStatus => write!(f, "GetStatus"),
+            Command::Raw(command) => write!(f, "Raw({})", command),
+        }
+    }
+}
+
+pub(crate) struct Redacted;
+
+impl Debug for Redacted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Overwrite a buffer which may contain key material.
+pub fn wipe<N: ArrayLength<u8>>(s: &mut String<N>) {
+    // Safety: zero bytes are valid UTF-8.
+    unsafe { s.as_bytes_mut() }.zeroize();
+    s.clear();
+}
+
 struct HexSlice<'a>(&'a [u8]);
 
 impl<'a> core::fmt::Display for HexSlice<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::result::Result<(), core::fmt::Error> {
         for b in self.0.iter() {
-            write!(f, "{:x}", b)?;
+            write!(f, "{:02x}", b)?;
         }
         Ok(())
     }
 }
 
 impl ConfigKey {
-    pub fn encode(&self, s: &mut CommandBuffer) {
+    /// True if the value of the key is a secret.
+    pub fn is_secret(&self) -> bool {
+        matches!(
+            self,
+            ConfigKey::AppKey | ConfigKey::AppsKey | ConfigKey::NwksKey
+        )
+    }
+
+    pub fn encode<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result {
         match self {
-            ConfigKey::DevAddr => {
-                s.push_str("dev_addr").unwrap();
-            }
-            ConfigKey::DevEui => {
-                s.push_str("dev_eui").unwrap();
-            }
-            ConfigKey::AppEui => {
-                s.push_str("app_eui").unwrap();
-            }
-            ConfigKey::AppKey => {
-                s.push_str("app_key").unwrap();
-            }
-            ConfigKey::NwksKey => {
-                s.push_str("nwks_key").unwrap();
-            }
-            ConfigKey::AppsKey => {
-                s.push_str("apps_key").unwrap();
-            }
-            ConfigKey::ChMask => {
-                s.push_str("ch_mask").unwrap();
-            }
-            ConfigKey::ChList => {
-                s.push_str("ch_list").unwrap();
-            }
+            ConfigKey::DevAddr => s.write_str("dev_addr"),
+            ConfigKey::DevEui => s.write_str("dev_eui"),
+            ConfigKey::AppEui => s.write_str("app_eui"),
+            ConfigKey::AppKey => s.write_str("app_key"),
+            ConfigKey::NwksKey => s.write_str("nwks_key"),
+            ConfigKey::AppsKey => s.write_str("apps_key"),
+            ConfigKey::ChMask => s.write_str("ch_mask"),
+            ConfigKey::ChList => s.write_str("ch_list"),
+            ConfigKey::Dr => s.write_str("dr"),
+            ConfigKey::Adr => s.write_str("adr"),
+            ConfigKey::PwrLevel => s.write_str("pwr_level"),
+            ConfigKey::Class => s.write_str("class"),
         }
     }
 }
 
 impl<'a> ConfigOption<'a> {
-    pub fn encode(&self, s: &mut CommandBuffer) {
+    pub fn key(&self) -> ConfigKey {
         match self {
-            ConfigOption::DevAddr(addr) => {
-                write!(s, "dev_addr:{}", addr).unwrap();
-            }
-            ConfigOption::DevEui(eui) => {
-                write!(s, "dev_eui:{}", eui,).unwrap();
-            }
-            ConfigOption::AppEui(eui) => {
-                write!(s, "app_eui:{}", eui,).unwrap();
-            }
-            ConfigOption::AppKey(key) => {
-                write!(s, "app_key:{}", key).unwrap();
-            }
-            ConfigOption::NwksKey(key) => {
-                write!(s, "nwks_key:{}", key,).unwrap();
-            }
-            ConfigOption::AppsKey(key) => {
-                write!(s, "apps_key:{}", key,).unwrap();
+            ConfigOption::DevAddr(_) => ConfigKey::DevAddr,
+            ConfigOption::DevEui(_) => ConfigKey::DevEui,
+            ConfigOption::AppEui(_) => ConfigKey::AppEui,
+            ConfigOption::AppKey(_) => ConfigKey::AppKey,
+            ConfigOption::NwksKey(_) => ConfigKey::NwksKey,
+            ConfigOption::AppsKey(_) => ConfigKey::AppsKey,
+            ConfigOption::ChMask(_, _) => ConfigKey::ChMask,
+            ConfigOption::Dr(_) => ConfigKey::Dr,
+            ConfigOption::Adr(_) => ConfigKey::Adr,
+            ConfigOption::PwrLevel(_) => ConfigKey::PwrLevel,
+            ConfigOption::Channel(_) => ConfigKey::ChList,
+            ConfigOption::Class(_) => ConfigKey::Class,
+        }
+    }
+
+    pub fn encode<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result {
+        self.key().encode(s)?;
+        s.write_char(':')?;
+        self.encode_value(s)
+    }
+
+    /// Encode the value of the option without the key.
+    pub fn encode_value<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result {
+        match self {
+            ConfigOption::DevAddr(addr) => write!(s, "{}", addr),
+            ConfigOption::DevEui(eui) => write!(s, "{}", eui),
+            ConfigOption::AppEui(eui) => write!(s, "{}", eui),
+            ConfigOption::AppKey(key) => write!(s, "{}", key),
+            ConfigOption::NwksKey(key) => write!(s, "{}", key),
+            ConfigOption::AppsKey(key) => write!(s, "{}", key),
+            ConfigOption::ChMask(id, mask) => write!(s, "{},{:04x}", id, mask),
+            ConfigOption::Dr(dr) => write!(s, "{}", dr),
+            ConfigOption::Adr(adr) => s.write_str(if *adr { "on" } else { "off" }),
+            ConfigOption::PwrLevel(level) => write!(s, "{}", level),
+            ConfigOption::Channel(channel) => write!(
+                s,
+                "{},{},{},{},{}",
+                channel.index,
+                if channel.enabled { "on" } else { "off" },
+                channel.frequency,
+                channel.min_dr,
+                channel.max_dr
+            ),
+            ConfigOption::Class(class) => {
+                let value = match class {
+                    DeviceClass::A => 0,
+                    DeviceClass::C => 2,
+                };
+                write!(s, "{}", value)
             }
+        }
+    }
+
+    /// Check if a value reported by the module is equal to the value of this option.
+    pub fn matches(&self, value: &str) -> bool {
+        let mut s = Command::buffer();
+        let matches = self.encode_value(&mut s).is_ok() && s.as_str().eq_ignore_ascii_case(value);
+        wipe(&mut s);
+        matches
+    }
+}
+
+impl<'a> Debug for ConfigOption<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigOption::DevAddr(addr) => f.debug_tuple("DevAddr").field(addr).finish(),
+            ConfigOption::DevEui(eui) => f.debug_tuple("DevEui").field(eui).finish(),
+            ConfigOption::AppEui(eui) => f.debug_tuple("AppEui").field(eui).finish(),
+            ConfigOption::AppKey(_) => f.debug_tuple("AppKey").field(&Redacted).finish(),
+            ConfigOption::NwksKey(_) => f.debug_tuple("NwksKey").field(&Redacted).finish(),
+            ConfigOption::AppsKey(_) => f.debug_tuple("AppsKey").field(&Redacted).finish(),
             ConfigOption::ChMask(id, mask) => {
-                write!(s, "ch_mask:{},{:04x}", id, mask).unwrap();
+                f.debug_tuple("ChMask").field(id).field(mask).finish()
             }
+            ConfigOption::Dr(dr) => f.debug_tuple("Dr").field(dr).finish(),
+            ConfigOption::Adr(adr) => f.debug_tuple("Adr").field(adr).finish(),
+            ConfigOption::PwrLevel(level) => f.debug_tuple("PwrLevel").field(level).finish(),
+            ConfigOption::Channel(channel) => f.debug_tuple("Channel").field(channel).finish(),
+            ConfigOption::Class(class) => f.debug_tuple("Class").field(class).finish(),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a> defmt::Format for ConfigOption<'a> {
+    fn format(&self, f: defmt::Formatter) {
+        use defmt::{write, Display2Format};
+        match self {
+            ConfigOption::DevAddr(addr) => write!(f, "DevAddr({})", Display2Format(*addr)),
+            ConfigOption::DevEui(eui) => write!(f, "DevEui({})", Display2Format(*eui)),
+            ConfigOption::AppEui(eui) => write!(f, "AppEui({})", Display2Format(*eui)),
+            ConfigOption::AppKey(_) => write!(f, "AppKey(<redacted>)"),
+            ConfigOption::NwksKey(_) => write!(f, "NwksKey(<redacted>)"),
+            ConfigOption::AppsKey(_) => write!(f, "AppsKey(<redacted>)"),
+            ConfigOption::ChMask(id, mask) => write!(f, "ChMask({}, {=u16:04x})", id, mask),
+            ConfigOption::Dr(dr) => write!(f, "Dr({})", dr),
+            ConfigOption::Adr(adr) => write!(f, "Adr({})", adr),
+            ConfigOption::PwrLevel(level) => write!(f, "PwrLevel({})", level),
+            ConfigOption::Channel(channel) => write!(f, "Channel({})", channel),
+            ConfigOption::Class(class) => write!(f, "Class({})", class),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Response {
+    fn format(&self, f: defmt::Formatter) {
+        use defmt::{write, Debug2Format};
+        match self {
+            Response::None => write!(f, "None"),
+            Response::Ok => write!(f, "Ok"),
+            Response::Error(code) => write!(f, "Error({})", code),
+            Response::FirmwareInfo(info) => write!(f, "FirmwareInfo({})", info),
+            Response::LoraBand(region) => write!(f, "LoraBand({})", Debug2Format(region)),
+            Response::Config(value) => write!(f, "Config({})", value.as_str()),
+            Response::Line(line) => write!(f, "Line({})", line.as_str()),
+            Response::Lines(handle) => write!(f, "Lines({})", handle),
+            Response::Custom(id, data) => write!(f, "Custom({}, {})", id, &data[..]),
+            Response::Recv(code, port, len, data) => write!(
+                f,
+                "Recv({}, {}, {}, {})",
+                code,
+                port,
+                len,
+                data
+            ),
+            Response::Status {
+                tx_ok,
+                tx_err,
+                rx_ok,
+                rx_timeout,
+                rx_err,
+                rssi,
+                snr,
+            } => write!(
+                f,
+                "Status {{ tx_ok: {}, tx_err: {}, rx_ok: {}, rx_timeout: {}, rx_err: {}, rssi: {}, snr: {} }}",
+                tx_ok,
+                tx_err,
+                rx_ok,
+                rx_timeout,
+                rx_err,
+                rssi,
+                snr
+            ),
+            Response::Initialized(region) => write!(f, "Initialized({})", Debug2Format(region)),
         }
     }
 }
 
 pub trait Encoder {
-    fn encode(&self, s: &mut CommandBuffer);
+    fn encode<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result;
 }
 
 pub trait Decoder {
@@ -244,12 +587,12 @@ pub trait Decoder {
 }
 
 impl Encoder for ConnectMode {
-    fn encode(&self, s: &mut CommandBuffer) {
+    fn encode<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result {
         let val = match self {
             ConnectMode::OTAA => "otaa",
             ConnectMode::ABP => "abp",
         };
-        s.push_str(val).unwrap();
+        s.write_str(val)
     }
 }
 
@@ -267,12 +610,12 @@ impl Decoder for ConnectMode {
 }
 
 impl Encoder for LoraMode {
-    fn encode(&self, s: &mut CommandBuffer) {
+    fn encode<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result {
         let val = match self {
             LoraMode::WAN => "0",
             LoraMode::P2P => "1",
         };
-        s.push_str(val).unwrap();
+        s.write_str(val)
     }
 }
 
@@ -290,7 +633,7 @@ impl Decoder for LoraMode {
 }
 
 impl Encoder for LoraRegion {
-    fn encode(&self, s: &mut CommandBuffer) {
+    fn encode<N: ArrayLength<u8>>(&self, s: &mut String<N>) -> fmt::Result {
         let val = match self {
             LoraRegion::EU868 => "EU868",
             LoraRegion::US915 => "US915",
@@ -300,7 +643,7 @@ impl Encoder for LoraRegion {
             LoraRegion::IN865 => "IN865",
             LoraRegion::UNKNOWN => "UNKNOWN",
         };
-        s.push_str(val).unwrap();
+        s.write_str(val)
     }
 }
 
@@ -342,8 +685,71 @@ impl EventCode {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [0xab; 16];
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn keys_are_redacted() {
+        let app_key = AppKey(KEY);
+        let nwks_key = NwksKey(KEY);
+        let apps_key = AppsKey(KEY);
+        let commands = [
+            Command::SetConfig(ConfigOption::AppKey(&app_key)),
+            Command::SetConfig(ConfigOption::NwksKey(&nwks_key)),
+            Command::SetConfig(ConfigOption::AppsKey(&apps_key)),
+            Command::SetConfigValue(ConfigKey::AppKey, "abababababababababababababababab"),
+        ];
+        for command in commands.iter() {
+            assert!(command.is_sensitive());
+            let mut s: String<consts::U128> = String::new();
+            write!(s, "{:?}", command).unwrap();
+            assert!(s.contains("<redacted>"), "{}", s);
+            assert!(!s.contains("abab"), "{}", s);
+        }
+        // The response carries the key
+        assert!(Command::GetConfig(ConfigKey::AppsKey).is_sensitive());
+        assert!(!Command::SetConfig(ConfigOption::Dr(5)).is_sensitive());
+        assert!(Command::Raw("at+set_config=app_key:00").is_sensitive());
+    }
+
+    #[test]
+    fn payload_bytes_are_two_hex_digits() {
+        let mut s = Command::buffer();
+        Command::Send(QoS::Unconfirmed, 1, &[0x01, 0x0a, 0xab])
+            .encode(&mut s)
+            .unwrap();
+        assert_eq!("at+send=0,1,010aab", s.as_str());
+    }
+
+    #[test]
+    fn commands_longer_than_the_buffer_fail() {
+        let mut s: String<consts::U16> = String::new();
+        let result = Command::Send(QoS::Unconfirmed, 1, &[0xab; 8]).encode(&mut s);
+        assert!(matches!(result, Err(DriverError::BufferTooSmall)));
+    }
+
+    #[test]
+    fn encoded_keys_are_wiped() {
+        let key = AppKey(KEY);
+        let mut s = Command::buffer();
+        Command::SetConfig(ConfigOption::AppKey(&key))
+            .encode(&mut s)
+            .unwrap();
+        assert_eq!(
+            "at+set_config=app_key:abababababababababababababababab",
+            s.as_str()
+        );
+        let (ptr, len) = (s.as_ptr(), s.len());
+        wipe(&mut s);
+        assert!(s.is_empty());
+        // Safety: the string storage is inline, and still holds `len` initialized bytes.
+        let wiped = unsafe { core::slice::from_raw_parts(ptr, len) };
+        assert!(wiped.iter().all(|b| *b == 0));
+    }
 }
@@ -0,0 +1,489 @@
+//! Regional parameters of the regions supported by the module, according to the
+//! LoRaWAN 1.0.2 regional parameters (revision B). AS923 assumes no dwell time limit.
+
+use crate::{Channel, LoraRegion};
+
+/// Modulation used at a data rate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DataRate {
+    /// LoRa modulation with the bandwidth in Hz.
+    Lora {
+        spreading_factor: u8,
+        bandwidth: u32,
+    },
+    /// FSK modulation with the bit rate in bits per second.
+    Fsk { bitrate: u32 },
+}
+
+/// Default frequency and data rate of the second receive window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rx2 {
+    /// Frequency in Hz.
+    pub frequency: u32,
+    pub data_rate: u8,
+}
+
+/// Size in bytes of the LoRaWAN header, port and MIC added to the application payload.
+pub const LORAWAN_OVERHEAD: usize = 13;
+
+const PREAMBLE_SYMBOLS: i64 = 8;
+
+impl DataRate {
+    /// Time on air in microseconds of a physical payload of `len` bytes, using the formula
+    /// from the Semtech SX1276 datasheet with explicit header, CRC and coding rate 4/5.
+    /// Low data rate optimization is used for SF11 and SF12 at 125 kHz. FSK frames have a
+    /// 5 byte preamble, 3 byte sync word, length byte and CRC.
+    pub fn time_on_air(&self, len: usize) -> u32 {
+        match *self {
+            DataRate::Lora {
+                spreading_factor,
+                bandwidth,
+            } => {
+                let sf = spreading_factor as i64;
+                let de = if sf >= 11 && bandwidth == 125_000 {
+                    1
+                } else {
+                    0
+                };
+                let numerator = 8 * len as i64 - 4 * sf + 28 + 16;
+                let denominator = 4 * (sf - 2 * de);
+                let blocks = ((numerator + denominator - 1) / denominator).max(0);
+                // Counted in quarter symbols, as the preamble is 4.25 symbols longer than configured
+                let quarter_symbols = (PREAMBLE_SYMBOLS * 4 + 17) + (8 + blocks * 5) * 4;
+                let us = quarter_symbols as u64 * (1 << sf) * 1_000_000 / (4 * bandwidth as u64);
+                us as u32
+            }
+            DataRate::Fsk { bitrate } => {
+                let bytes = (5 + 3 + 1 + len + 2) as u64;
+                (bytes * 8 * 1_000_000 / bitrate as u64) as u32
+            }
+        }
+    }
+}
+
+const fn lora(spreading_factor: u8, bandwidth: u32) -> Option<DataRate> {
+    Some(DataRate::Lora {
+        spreading_factor,
+        bandwidth,
+    })
+}
+
+const FSK: Option<DataRate> = Some(DataRate::Fsk { bitrate: 50_000 });
+
+const EU868_DR: [Option<DataRate>; 8] = [
+    lora(12, 125_000),
+    lora(11, 125_000),
+    lora(10, 125_000),
+    lora(9, 125_000),
+    lora(8, 125_000),
+    lora(7, 125_000),
+    lora(7, 250_000),
+    FSK,
+];
+
+const US915_DR: [Option<DataRate>; 14] = [
+    lora(10, 125_000),
+    lora(9, 125_000),
+    lora(8, 125_000),
+    lora(7, 125_000),
+    lora(8, 500_000),
+    None,
+    None,
+    None,
+    lora(12, 500_000),
+    lora(11, 500_000),
+    lora(10, 500_000),
+    lora(9, 500_000),
+    lora(8, 500_000),
+    lora(7, 500_000),
+];
+
+const AU915_DR: [Option<DataRate>; 14] = [
+    lora(12, 125_000),
+    lora(11, 125_000),
+    lora(10, 125_000),
+    lora(9, 125_000),
+    lora(8, 125_000),
+    lora(7, 125_000),
+    lora(8, 500_000),
+    None,
+    lora(12, 500_000),
+    lora(11, 500_000),
+    lora(10, 500_000),
+    lora(9, 500_000),
+    lora(8, 500_000),
+    lora(7, 500_000),
+];
+
+const KR920_DR: [Option<DataRate>; 6] = [
+    lora(12, 125_000),
+    lora(11, 125_000),
+    lora(10, 125_000),
+    lora(9, 125_000),
+    lora(8, 125_000),
+    lora(7, 125_000),
+];
+
+const AS923_DR: [Option<DataRate>; 8] = EU868_DR;
+
+const IN865_DR: [Option<DataRate>; 8] = [
+    lora(12, 125_000),
+    lora(11, 125_000),
+    lora(10, 125_000),
+    lora(9, 125_000),
+    lora(8, 125_000),
+    lora(7, 125_000),
+    None,
+    FSK,
+];
+
+// Maximum application payload size per uplink data rate, without MAC commands in FOpts.
+const EU868_PAYLOAD: [u8; 8] = [51, 51, 51, 115, 242, 242, 242, 242];
+const US915_PAYLOAD: [u8; 5] = [11, 53, 125, 242, 242];
+const AU915_PAYLOAD: [u8; 7] = [51, 51, 51, 115, 242, 242, 242];
+const KR920_PAYLOAD: [u8; 6] = [51, 51, 51, 115, 242, 242];
+const AS923_PAYLOAD: [u8; 8] = [51, 51, 51, 115, 242, 242, 242, 242];
+const IN865_PAYLOAD: [u8; 8] = [51, 51, 51, 115, 242, 242, 0, 242];
+
+const EU868_CHANNELS: [u32; 3] = [868_100_000, 868_300_000, 868_500_000];
+const KR920_CHANNELS: [u32; 3] = [922_100_000, 922_300_000, 922_500_000];
+const AS923_CHANNELS: [u32; 2] = [923_200_000, 923_400_000];
+const IN865_CHANNELS: [u32; 3] = [865_062_500, 865_402_500, 865_985_000];
+
+/// Modulation used at the data rate in the region, or `None` if the data rate is not defined.
+pub fn data_rate(region: LoraRegion, dr: u8) -> Option<DataRate> {
+    let table: &[Option<DataRate>] = match region {
+        LoraRegion::EU868 => &EU868_DR,
+        LoraRegion::US915 => &US915_DR,
+        LoraRegion::AU915 => &AU915_DR,
+        LoraRegion::KR920 => &KR920_DR,
+        LoraRegion::AS923 => &AS923_DR,
+        LoraRegion::IN865 => &IN865_DR,
+        LoraRegion::UNKNOWN => return None,
+    };
+    table.get(dr as usize).copied().flatten()
+}
+
+/// Time on air in microseconds of an uplink with `len` bytes of application payload in the
+/// region at the given data rate, including the LoRaWAN overhead.
+pub fn time_on_air(region: LoraRegion, dr: u8, len: usize) -> Option<u32> {
+    data_rate(region, dr).map(|dr| dr.time_on_air(len + LORAWAN_OVERHEAD))
+}
+
+/// Maximum application payload size in bytes for uplinks in the region at the given data rate,
+/// or `None` if the data rate is not valid for uplinks in the region.
+pub fn max_payload_len(region: LoraRegion, dr: u8) -> Option<usize> {
+    let table: &[u8] = match region {
+        LoraRegion::EU868 => &EU868_PAYLOAD,
+        LoraRegion::US915 => &US915_PAYLOAD,
+        LoraRegion::AU915 => &AU915_PAYLOAD,
+        LoraRegion::KR920 => &KR920_PAYLOAD,
+        LoraRegion::AS923 => &AS923_PAYLOAD,
+        LoraRegion::IN865 => &IN865_PAYLOAD,
+        LoraRegion::UNKNOWN => return None,
+    };
+    match table.get(dr as usize) {
+        Some(0) | None => None,
+        Some(len) => Some(*len as usize),
+    }
+}
+
+/// Maximum EIRP in dBm allowed in the region.
+pub fn max_eirp(region: LoraRegion) -> Option<i8> {
+    match region {
+        LoraRegion::EU868 | LoraRegion::AS923 => Some(16),
+        LoraRegion::KR920 => Some(14),
+        LoraRegion::US915 | LoraRegion::AU915 | LoraRegion::IN865 => Some(30),
+        LoraRegion::UNKNOWN => None,
+    }
+}
+
+/// Transmit power in dBm for the power index, as set using `ConfigOption::PwrLevel`. Each index
+/// lowers the power by 2 dB from the maximum.
+pub fn tx_power(region: LoraRegion, index: u8) -> Option<i8> {
+    let max_index = match region {
+        LoraRegion::US915 | LoraRegion::AU915 | LoraRegion::IN865 => 10,
+        _ => 7,
+    };
+    if index > max_index {
+        return None;
+    }
+    max_eirp(region).map(|eirp| eirp - 2 * index as i8)
+}
+
+/// Number of channels the region supports.
+pub fn max_channels(region: LoraRegion) -> u8 {
+    match region {
+        LoraRegion::US915 | LoraRegion::AU915 => 72,
+        LoraRegion::UNKNOWN => 0,
+        _ => 16,
+    }
+}
+
+/// Default channel at the index, enabled. In US915 and AU915 all 72 channels are defined by
+/// the region, other regions define two or three channels.
+pub fn default_channel(region: LoraRegion, index: u8) -> Option<Channel> {
+    let channel = |frequency, min_dr, max_dr| Channel {
+        index,
+        enabled: true,
+        frequency,
+        min_dr,
+        max_dr,
+    };
+    let i = index as u32;
+    match region {
+        LoraRegion::US915 if index < 64 => Some(channel(902_300_000 + i * 200_000, 0, 3)),
+        LoraRegion::US915 if index < 72 => Some(channel(903_000_000 + (i - 64) * 1_600_000, 4, 4)),
+        LoraRegion::AU915 if index < 64 => Some(channel(915_200_000 + i * 200_000, 0, 5)),
+        LoraRegion::AU915 if index < 72 => Some(channel(915_900_000 + (i - 64) * 1_600_000, 6, 6)),
+        LoraRegion::EU868 => EU868_CHANNELS
+            .get(index as usize)
+            .map(|f| channel(*f, 0, 5)),
+        LoraRegion::KR920 => KR920_CHANNELS
+            .get(index as usize)
+            .map(|f| channel(*f, 0, 5)),
+        LoraRegion::AS923 => AS923_CHANNELS
+            .get(index as usize)
+            .map(|f| channel(*f, 0, 5)),
+        LoraRegion::IN865 => IN865_CHANNELS
+            .get(index as usize)
+            .map(|f| channel(*f, 0, 5)),
+        _ => None,
+    }
+}
+
+/// Frequencies in Hz which may be used in the region, as an inclusive range.
+pub fn frequency_range(region: LoraRegion) -> Option<(u32, u32)> {
+    match region {
+        LoraRegion::EU868 => Some((863_000_000, 870_000_000)),
+        LoraRegion::US915 => Some((902_000_000, 928_000_000)),
+        LoraRegion::AU915 => Some((915_000_000, 928_000_000)),
+        LoraRegion::KR920 => Some((920_900_000, 923_300_000)),
+        LoraRegion::AS923 => Some((915_000_000, 928_000_000)),
+        LoraRegion::IN865 => Some((865_000_000, 867_000_000)),
+        LoraRegion::UNKNOWN => None,
+    }
+}
+
+/// Frequency band with a duty cycle limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubBand {
+    /// Lowest frequency in Hz, inclusive.
+    pub min_frequency: u32,
+    /// Highest frequency in Hz, inclusive.
+    pub max_frequency: u32,
+    /// Share of time a device may transmit, in parts per thousand.
+    pub duty_cycle_permille: u16,
+}
+
+const fn sub_band(min_frequency: u32, max_frequency: u32, duty_cycle_permille: u16) -> SubBand {
+    SubBand {
+        min_frequency,
+        max_frequency,
+        duty_cycle_permille,
+    }
+}
+
+// ETSI EN 300 220 sub-bands used by EU868.
+const EU868_SUB_BANDS: [SubBand; 6] = [
+    sub_band(863_000_000, 864_999_999, 1),
+    sub_band(865_000_000, 867_999_999, 10),
+    sub_band(868_000_000, 868_600_000, 10),
+    sub_band(868_700_000, 869_200_000, 1),
+    sub_band(869_400_000, 869_650_000, 100),
+    sub_band(869_700_000, 870_000_000, 10),
+];
+
+/// Sub-bands of the region with duty cycle limits. Regions without such limits have none.
+pub fn sub_bands(region: LoraRegion) -> &'static [SubBand] {
+    match region {
+        LoraRegion::EU868 => &EU868_SUB_BANDS,
+        _ => &[],
+    }
+}
+
+/// Number of sub-bands in US915 and AU915, each made up of eight 125 kHz channels and one
+/// 500 kHz channel. Other regions are not divided this way, and have none.
+pub fn sub_band_count(region: LoraRegion) -> u8 {
+    match region {
+        LoraRegion::US915 | LoraRegion::AU915 => 8,
+        _ => 0,
+    }
+}
+
+/// Channel mask with the given id, as set using `ConfigOption::ChMask`, which enables only the
+/// channels of the sub-band. Sub-bands are numbered from 1.
+pub fn sub_band_mask(region: LoraRegion, sub_band: u8, id: u8) -> Option<u16> {
+    if sub_band == 0 || sub_band > sub_band_count(region) || id as u16 * 16 >= 72 {
+        return None;
+    }
+    let index = sub_band - 1;
+    Some(match id {
+        // 125 kHz channels 0 to 63
+        id if id == index / 2 => 0xff << ((index % 2) * 8),
+        // 500 kHz channels 64 to 71
+        4 => 1 << index,
+        _ => 0,
+    })
+}
+
+/// Default second receive window parameters of the region.
+pub fn rx2(region: LoraRegion) -> Option<Rx2> {
+    let (frequency, data_rate) = match region {
+        LoraRegion::EU868 => (869_525_000, 0),
+        LoraRegion::US915 | LoraRegion::AU915 => (923_300_000, 8),
+        LoraRegion::KR920 => (921_900_000, 0),
+        LoraRegion::AS923 => (923_200_000, 2),
+        LoraRegion::IN865 => (866_550_000, 2),
+        LoraRegion::UNKNOWN => return None,
+    };
+    Some(Rx2 {
+        frequency,
+        data_rate,
+    })
+}
+
+/// Check that the second receive window parameters are valid in the region.
+pub fn is_valid_rx2(region: LoraRegion, frequency: u32, dr: u8) -> bool {
+    matches!(frequency_range(region), Some((min, max)) if frequency >= min && frequency <= max)
+        && data_rate(region, dr).is_some()
+}
+
+/// Check that the channel entry is valid in the region. Channels defined by the region keep their
+/// frequency, and other channels may be removed by disabling them with a frequency of 0.
+pub fn is_valid_channel(region: LoraRegion, channel: &Channel) -> bool {
+    if channel.index >= max_channels(region) {
+        return false;
+    }
+    let valid_frequency = match default_channel(region, channel.index) {
+        Some(default) => channel.frequency == default.frequency,
+        None if !channel.enabled && channel.frequency == 0 => return true,
+        None => matches!(frequency_range(region), Some((min, max))
+            if channel.frequency >= min && channel.frequency <= max),
+    };
+    valid_frequency
+        && channel.min_dr <= channel.max_dr
+        && data_rate(region, channel.max_dr).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_limits() {
+        assert_eq!(Some(51), max_payload_len(LoraRegion::EU868, 0));
+        assert_eq!(Some(242), max_payload_len(LoraRegion::EU868, 5));
+        assert_eq!(Some(11), max_payload_len(LoraRegion::US915, 0));
+        assert_eq!(None, max_payload_len(LoraRegion::US915, 8));
+        assert_eq!(None, max_payload_len(LoraRegion::IN865, 6));
+        assert_eq!(None, max_payload_len(LoraRegion::UNKNOWN, 0));
+    }
+
+    #[test]
+    fn data_rates() {
+        assert_eq!(lora(12, 125_000), data_rate(LoraRegion::EU868, 0),);
+        assert_eq!(FSK, data_rate(LoraRegion::EU868, 7));
+        assert_eq!(lora(8, 500_000), data_rate(LoraRegion::US915, 4));
+        assert_eq!(None, data_rate(LoraRegion::US915, 5));
+        assert_eq!(lora(12, 500_000), data_rate(LoraRegion::AU915, 8));
+        assert_eq!(None, data_rate(LoraRegion::KR920, 6));
+    }
+
+    #[test]
+    fn airtime() {
+        assert_eq!(Some(46_336), time_on_air(LoraRegion::EU868, 5, 0));
+        assert_eq!(Some(1_155_072), time_on_air(LoraRegion::EU868, 0, 0));
+        assert_eq!(Some(3_840), time_on_air(LoraRegion::EU868, 7, 0));
+        assert_eq!(Some(71_936), time_on_air(LoraRegion::EU868, 5, 20));
+        assert_eq!(None, time_on_air(LoraRegion::US915, 5, 0));
+    }
+
+    #[test]
+    fn tx_power_and_eirp() {
+        assert_eq!(Some(16), tx_power(LoraRegion::EU868, 0));
+        assert_eq!(Some(2), tx_power(LoraRegion::EU868, 7));
+        assert_eq!(None, tx_power(LoraRegion::EU868, 8));
+        assert_eq!(Some(10), tx_power(LoraRegion::US915, 10));
+    }
+
+    #[test]
+    fn sub_band_masks() {
+        assert_eq!(Some(0xff00), sub_band_mask(LoraRegion::US915, 2, 0));
+        assert_eq!(Some(0x0000), sub_band_mask(LoraRegion::US915, 2, 1));
+        assert_eq!(Some(0x0002), sub_band_mask(LoraRegion::US915, 2, 4));
+        assert_eq!(Some(0x00ff), sub_band_mask(LoraRegion::AU915, 7, 3));
+        assert_eq!(None, sub_band_mask(LoraRegion::US915, 9, 0));
+        assert_eq!(None, sub_band_mask(LoraRegion::US915, 1, 5));
+        assert_eq!(None, sub_band_mask(LoraRegion::EU868, 1, 0));
+    }
+
+    #[test]
+    fn channels() {
+        assert_eq!(
+            868_500_000,
+            default_channel(LoraRegion::EU868, 2).unwrap().frequency
+        );
+        assert_eq!(None, default_channel(LoraRegion::EU868, 3));
+        assert_eq!(
+            914_900_000,
+            default_channel(LoraRegion::US915, 63).unwrap().frequency
+        );
+        let channel = default_channel(LoraRegion::AU915, 71).unwrap();
+        assert_eq!((927_100_000, 6), (channel.frequency, channel.min_dr));
+        assert_eq!(
+            Some(Rx2 {
+                frequency: 869_525_000,
+                data_rate: 0
+            }),
+            rx2(LoraRegion::EU868)
+        );
+        assert!(is_valid_rx2(LoraRegion::US915, 923_300_000, 8));
+        assert!(!is_valid_rx2(LoraRegion::EU868, 923_300_000, 0));
+    }
+
+    #[test]
+    fn channel_validation() {
+        let channel = |index, enabled, frequency, min_dr, max_dr| Channel {
+            index,
+            enabled,
+            frequency,
+            min_dr,
+            max_dr,
+        };
+        let region = LoraRegion::EU868;
+        assert!(is_valid_channel(
+            region,
+            &channel(3, true, 867_100_000, 0, 5)
+        ));
+        assert!(is_valid_channel(region, &channel(3, false, 0, 0, 0)));
+        assert!(is_valid_channel(
+            region,
+            &channel(0, false, 868_100_000, 0, 5)
+        ));
+        assert!(!is_valid_channel(region, &channel(0, false, 0, 0, 0)));
+        assert!(!is_valid_channel(
+            region,
+            &channel(3, true, 915_000_000, 0, 5)
+        ));
+        assert!(!is_valid_channel(
+            region,
+            &channel(3, true, 867_100_000, 5, 0)
+        ));
+        assert!(!is_valid_channel(
+            region,
+            &channel(3, true, 867_100_000, 0, 8)
+        ));
+        assert!(!is_valid_channel(
+            region,
+            &channel(16, true, 867_100_000, 0, 5)
+        ));
+        assert!(!is_valid_channel(
+            LoraRegion::US915,
+            &channel(8, true, 904_000_000, 0, 3)
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::regions::{self, SubBand};
+use crate::{Channel, DriverError, LoraRegion, Port, QoS, Rak811Driver, Response, UplinkResult};
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::{serial::Read, serial::Write};
+use heapless::{consts, ArrayLength, Vec};
+
+const BUCKETS: usize = 12;
+
+const DAY_MS: u64 = 24 * 3_600_000;
+
+// Airtime used over a sliding window, counted in buckets of a twelfth of the window. Airtime
+// is released when its bucket leaves the window, so limits are applied conservatively.
+#[derive(Debug, Clone, Copy)]
+struct AirtimeWindow {
+    bucket_ms: u64,
+    budget_us: u64,
+    // Bucket number since time 0, and airtime in microseconds
+    buckets: [(u64, u64); BUCKETS],
+}
+
+impl AirtimeWindow {
+    fn new(window_ms: u64, budget_us: u64) -> Self {
+        AirtimeWindow {
+            bucket_ms: core::cmp::max(window_ms / BUCKETS as u64, 1),
+            budget_us,
+            buckets: [(0, 0); BUCKETS],
+        }
+    }
+
+    fn used(&self, now_ms: u64) -> u64 {
+        let current = now_ms / self.bucket_ms;
+        self.buckets
+            .iter()
+            .filter(|(n, _)| *n <= current && *n + BUCKETS as u64 > current)
+            .map(|(_, us)| us)
+            .sum()
+    }
+
+    fn record(&mut self, now_ms: u64, airtime_us: u32) {
+        let current = now_ms / self.bucket_ms;
+        let bucket = &mut self.buckets[(current % BUCKETS as u64) as usize];
+        if bucket.0 != current {
+            *bucket = (current, 0);
+        }
+        bucket.1 += airtime_us as u64;
+    }
+
+    fn earliest(&self, now_ms: u64, airtime_us: u32) -> Option<u64> {
+        let airtime_us = airtime_us as u64;
+        if airtime_us > self.budget_us {
+            return None;
+        }
+        let mut used = self.used(now_ms);
+        if used + airtime_us <= self.budget_us {
+            return Some(now_ms);
+        }
+        let current = now_ms / self.bucket_ms;
+        let oldest = (current + 1).saturating_sub(BUCKETS as u64);
+        for n in oldest..=current {
+            if let Some((_, us)) = self.buckets.iter().find(|(b, _)| *b == n) {
+                used -= us;
+                if used + airtime_us <= self.budget_us {
+                    return Some((n + BUCKETS as u64) * self.bucket_ms);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Behaviour when an uplink is not allowed yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SchedulePolicy {
+    /// Return `nb::Error::WouldBlock` until the uplink is allowed.
+    Defer,
+    /// Fail with `DriverError::DutyCycleLimited`.
+    Reject,
+}
+
+/// Airtime limits applied by an `UplinkScheduler`.
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulerConfig {
+    pub policy: SchedulePolicy,
+    /// Apply the duty cycle limits of the sub-bands of the region.
+    pub duty_cycle: bool,
+    /// Window over which the duty cycle is measured, in milliseconds.
+    pub window_ms: u64,
+    /// Airtime allowed per 24 hours, in milliseconds, such as the 30 seconds of the TTN
+    /// fair access policy. `None` applies no limit.
+    pub fair_access_ms: Option<u32>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            policy: SchedulePolicy::Defer,
+            duty_cycle: true,
+            window_ms: 3_600_000,
+            fair_access_ms: Some(30_000),
+        }
+    }
+}
+
+/// Tracks the airtime of uplinks, and holds back uplinks that would exceed the duty cycle
+/// limits of the region or the fair access policy.
+///
+/// As the module selects the channel of each uplink, airtime is counted against every
+/// sub-band with an enabled channel. Like the `JoinManager`, the scheduler does not keep
+/// time itself, and is passed the current time in milliseconds.
+pub struct UplinkScheduler {
+    config: SchedulerConfig,
+    // Sub-band, its airtime, and whether any channel in use falls within it
+    sub_bands: Vec<(SubBand, AirtimeWindow, bool), consts::U8>,
+    fair_access: Option<AirtimeWindow>,
+}
+
+impl UplinkScheduler {
+    /// Create a scheduler for the region, assuming the default channels of the region are used.
+    pub fn new(region: LoraRegion, config: SchedulerConfig) -> Self {
+        let mut sub_bands = Vec::new();
+        if config.duty_cycle {
+            for band in regions::sub_bands(region) {
+                let budget_us = config.window_ms * band.duty_cycle_permille as u64;
+                let window = AirtimeWindow::new(config.window_ms, budget_us);
+                sub_bands.push((*band, window, false)).ok();
+            }
+        }
+        let mut scheduler = UplinkScheduler {
+            config,
+            sub_bands,
+            fair_access: config
+                .fair_access_ms
+                .map(|ms| AirtimeWindow::new(DAY_MS, ms as u64 * 1000)),
+        };
+        let mut channels: Vec<Channel, consts::U72> = Vec::new();
+        for i in 0..regions::max_channels(region) {
+            if let Some(channel) = regions::default_channel(region, i) {
+                channels.push(channel).ok();
+            }
+        }
+        scheduler.set_channels(&channels);
+        scheduler
+    }
+
+    /// Set the channels the module may use, such as read using `Rak811Driver::channel_list`.
+    pub fn set_channels(&mut self, channels: &[Channel]) {
+        for (band, _, in_use) in self.sub_bands.iter_mut() {
+            *in_use = channels.iter().any(|c| {
+                c.enabled && c.frequency >= band.min_frequency && c.frequency <= band.max_frequency
+            });
+        }
+    }
+
+    /// Earliest time in milliseconds an uplink occupying the air for `airtime_us` microseconds
+    /// may be sent, or `None` if it exceeds the limits altogether.
+    pub fn earliest(&self, now_ms: u64, airtime_us: u32) -> Option<u64> {
+        let mut earliest = now_ms;
+        let windows = self
+            .sub_bands
+            .iter()
+            .filter(|(_, _, in_use)| *in_use)
+            .map(|(_, window, _)| window)
+            .chain(self.fair_access.iter());
+        for window in windows {
+            earliest = earliest.max(window.earliest(now_ms, airtime_us)?);
+        }
+        Some(earliest)
+    }
+
+    /// Earliest time in milliseconds an uplink with `len` bytes of payload may be sent at the
+    /// current data rate of the driver.
+    pub fn earliest_send<W, R, RST, BUF, RXQ, CMD>(
+        &self,
+        driver: &mut Rak811Driver<W, R, RST, BUF, RXQ, CMD>,
+        now_ms: u64,
+        len: usize,
+    ) -> Result<Option<u64>, DriverError>
+    where
+        W: Write<u8>,
+        R: Read<u8>,
+        RST: OutputPin,
+        BUF: ArrayLength<u8>,
+        RXQ: ArrayLength<Response>,
+        CMD: ArrayLength<u8>,
+    {
+        let airtime_us = driver.time_on_air(len)?;
+        Ok(self.earliest(now_ms, airtime_us))
+    }
+
+    /// Record an uplink sent at the given time.
+    pub fn record(&mut self, now_ms: u64, airtime_us: u32) {
+        for (_, window, _) in self.sub_bands.iter_mut().filter(|(_, _, in_use)| *in_use) {
+            window.record(now_ms, airtime_us);
+        }
+        if let Some(window) = &mut self.fair_access {
+            window.record(now_ms, airtime_us);
+        }
+    }
+
+    /// Send the data using the driver if the limits allow it, and record its airtime. Otherwise
+    /// the uplink is deferred or rejected according to the policy. If the uplink exceeds the
+    /// limits altogether, `DriverError::DutyCycleLimited(None)` is returned regardless of policy.
+    pub fn send<W, R, RST, BUF, RXQ, CMD>(
+        &mut self,
+        driver: &mut Rak811Driver<W, R, RST, BUF, RXQ, CMD>,
+        now_ms: u64,
+        qos: QoS,
+        port: Port,
+        data: &[u8],
+    ) -> nb::Result<UplinkResult, DriverError>
+    where
+        W: Write<u8>,
+        R: Read<u8>,
+        RST: OutputPin,
+        BUF: ArrayLength<u8>,
+        RXQ: ArrayLength<Response>,
+        CMD: ArrayLength<u8>,
+    {
+        let airtime_us = driver.time_on_air(data.len())?;
+        match self.earliest(now_ms, airtime_us) {
+            Some(earliest) if earliest <= now_ms => {}
+            Some(earliest) if self.config.policy == SchedulePolicy::Defer => {
+                debug!("Deferring uplink until {}", earliest);
+                return Err(nb::Error::WouldBlock);
+            }
+            earliest => {
+                warn!("Uplink rejected by duty cycle limits");
+                return Err(nb::Error::Other(DriverError::DutyCycleLimited(earliest)));
+            }
+        }
+        driver.start_send(qos, port, data)?;
+        self.record(now_ms, airtime_us);
+        nb::block!(driver.poll_send()).map_err(nb::Error::Other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_releases_oldest_bucket() {
+        // 1200 ms window with 100 ms buckets and 100 us budget
+        let mut window = AirtimeWindow::new(1200, 100);
+        window.record(50, 60);
+        window.record(450, 30);
+        assert_eq!(Some(500), window.earliest(500, 10));
+        assert_eq!(Some(1200), window.earliest(500, 50));
+        assert_eq!(Some(1600), window.earliest(500, 100));
+        assert_eq!(None, window.earliest(500, 101));
+        assert_eq!(30, window.used(1250));
+    }
+
+    #[test]
+    fn eu868_duty_cycle() {
+        let config = SchedulerConfig {
+            fair_access_ms: None,
+            ..Default::default()
+        };
+        let mut scheduler = UplinkScheduler::new(LoraRegion::EU868, config);
+        // 1% of an hour is 36 s
+        scheduler.record(0, 30_000_000);
+        assert_eq!(Some(0), scheduler.earliest(0, 6_000_000));
+        assert_eq!(Some(3_600_000), scheduler.earliest(0, 7_000_000));
+
+        // No limits apply in US915 without fair access policy
+        let scheduler = UplinkScheduler::new(LoraRegion::US915, config);
+        assert_eq!(Some(0), scheduler.earliest(0, 100_000_000));
+    }
+}
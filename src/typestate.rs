@@ -0,0 +1,355 @@
+// The wrapper is handed back on failed transitions, so errors are as large as the driver.
+#![allow(clippy::result_large_err)]
+
+use crate::{
+    AppKey, AppsKey, ConnectMode, DevAddr, DeviceClass, Downlink, DriverError, JoinManager,
+    LoraMode, LoraRegion, NwksKey, PayloadHandle, Port, QoS, Rak811Driver, RepeatedDownlinks,
+    Response, UplinkResult, EUI,
+};
+use core::marker::PhantomData;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::{serial::Read, serial::Write};
+use heapless::{consts, ArrayLength};
+
+/// Band and mode of operation not yet set.
+pub struct Unconfigured;
+
+/// Configured for LoRaWAN mode. The parameter tracks if the node has joined a network.
+pub struct LoraWan<J> {
+    _join: PhantomData<J>,
+}
+
+pub struct NotJoined;
+
+pub struct Joined;
+
+/// Result of a state transition. On failure, the wrapper is handed back in its original state.
+pub type Transition<
+    W,
+    R,
+    RST,
+    From,
+    To,
+    E = DriverError,
+    BUF = consts::U512,
+    RXQ = consts::U4,
+    CMD = consts::U128,
+> = Result<Rak811<W, R, RST, To, BUF, RXQ, CMD>, (Rak811<W, R, RST, From, BUF, RXQ, CMD>, E)>;
+
+type JoinTransition<W, R, RST, BUF, RXQ, CMD, E> =
+    Transition<W, R, RST, LoraWan<NotJoined>, LoraWan<Joined>, E, BUF, RXQ, CMD>;
+
+/// A `Rak811Driver` wrapper which only exposes the operations valid in the state `S`. Peer to
+/// peer mode is not supported by the wrapper.
+///
+/// Sending before joining fails to compile:
+///
+/// ```compile_fail
+/// # use drogue_rak811::*;
+/// # use embedded_hal::{digital::v2::OutputPin, serial::Read, serial::Write};
+/// # fn run<W: Write<u8>, R: Read<u8>, RST: OutputPin>(driver: Rak811Driver<W, R, RST>) {
+/// let mut node = Rak811::new(driver)
+///     .into_lorawan(LoraRegion::EU868)
+///     .map_err(|(_, e)| e)
+///     .unwrap();
+/// node.send(QoS::Confirmed, 1, b"hello!").unwrap();
+/// # }
+/// ```
+///
+/// As does joining before the band and mode are configured:
+///
+/// ```compile_fail
+/// # use drogue_rak811::*;
+/// # use embedded_hal::{digital::v2::OutputPin, serial::Read, serial::Write};
+/// # fn run<W: Write<u8>, R: Read<u8>, RST: OutputPin>(driver: Rak811Driver<W, R, RST>) {
+/// let node = Rak811::new(driver).join(ConnectMode::OTAA);
+/// # }
+/// ```
+///
+/// Configuring and joining first:
+///
+/// ```no_run
+/// # use drogue_rak811::*;
+/// # use embedded_hal::{digital::v2::OutputPin, serial::Read, serial::Write};
+/// # fn run<W: Write<u8>, R: Read<u8>, RST: OutputPin>(driver: Rak811Driver<W, R, RST>) {
+/// let node = Rak811::new(driver)
+///     .into_lorawan(LoraRegion::EU868)
+///     .map_err(|(_, e)| e)
+///     .unwrap();
+/// let mut node = node.join(ConnectMode::OTAA).map_err(|(_, e)| e).unwrap();
+/// node.send(QoS::Confirmed, 1, b"hello!").unwrap();
+/// # }
+/// ```
+pub struct Rak811<W, R, RST, S, BUF = consts::U512, RXQ = consts::U4, CMD = consts::U128>
+where
+    W: Write<u8>,
+    R: Read<u8>,
+    RST: OutputPin,
+    BUF: ArrayLength<u8>,
+    RXQ: ArrayLength<Response>,
+    CMD: ArrayLength<u8>,
+{
+    driver: Rak811Driver<W, R, RST, BUF, RXQ, CMD>,
+    _state: PhantomData<S>,
+}
+
+impl<W, R, RST, S, BUF, RXQ, CMD> Rak811<W, R, RST, S, BUF, RXQ, CMD>
+where
+    W: Write<u8>,
+    R: Read<u8>,
+    RST: OutputPin,
+    BUF: ArrayLength<u8>,
+    RXQ: ArrayLength<Response>,
+    CMD: ArrayLength<u8>,
+{
+    fn transition<T>(self) -> Rak811<W, R, RST, T, BUF, RXQ, CMD> {
+        Rak811 {
+            driver: self.driver,
+            _state: PhantomData,
+        }
+    }
+
+    /// Release the underlying driver.
+    pub fn into_inner(self) -> Rak811Driver<W, R, RST, BUF, RXQ, CMD> {
+        self.driver
+    }
+
+    /// See `Rak811Driver::process`.
+    pub fn process(&mut self) -> Result<(), DriverError> {
+        self.driver.process()
+    }
+
+    /// See `Rak811Driver::digest`.
+    pub fn digest(&mut self) -> Result<(), DriverError> {
+        self.driver.digest()
+    }
+
+    /// See `Rak811Driver::payload`.
+    pub fn payload(&self, handle: &PayloadHandle) -> Option<&[u8]> {
+        self.driver.payload(handle)
+    }
+
+    /// See `Rak811Driver::release`.
+    pub fn release(&mut self, handle: PayloadHandle) {
+        self.driver.release(handle)
+    }
+}
+
+impl<W, R, RST, BUF, RXQ, CMD> Rak811<W, R, RST, Unconfigured, BUF, RXQ, CMD>
+where
+    W: Write<u8>,
+    R: Read<u8>,
+    RST: OutputPin,
+    BUF: ArrayLength<u8>,
+    RXQ: ArrayLength<Response>,
+    CMD: ArrayLength<u8>,
+{
+    pub fn new(driver: Rak811Driver<W, R, RST, BUF, RXQ, CMD>) -> Self {
+        Rak811 {
+            driver,
+            _state: PhantomData,
+        }
+    }
+
+    /// Configure the module for LoRaWAN in the given region.
+    pub fn into_lorawan(
+        mut self,
+        band: LoraRegion,
+    ) -> Transition<W, R, RST, Unconfigured, LoraWan<NotJoined>, DriverError, BUF, RXQ, CMD> {
+        match self.configure(band) {
+            Ok(_) => Ok(self.transition()),
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    fn configure(&mut self, band: LoraRegion) -> Result<(), DriverError> {
+        self.driver.set_band(band)?;
+        self.driver.set_mode(LoraMode::WAN)
+    }
+}
+
+impl<W, R, RST, BUF, RXQ, CMD> Rak811<W, R, RST, LoraWan<NotJoined>, BUF, RXQ, CMD>
+where
+    W: Write<u8>,
+    R: Read<u8>,
+    RST: OutputPin,
+    BUF: ArrayLength<u8>,
+    RXQ: ArrayLength<Response>,
+    CMD: ArrayLength<u8>,
+{
+    pub fn set_device_address(&mut self, addr: &DevAddr) -> Result<(), DriverError> {
+        self.driver.set_device_address(addr)
+    }
+
+    pub fn set_device_eui(&mut self, eui: &EUI) -> Result<(), DriverError> {
+        self.driver.set_device_eui(eui)
+    }
+
+    pub fn set_app_eui(&mut self, eui: &EUI) -> Result<(), DriverError> {
+        self.driver.set_app_eui(eui)
+    }
+
+    pub fn set_app_key(&mut self, key: &AppKey) -> Result<(), DriverError> {
+        self.driver.set_app_key(key)
+    }
+
+    pub fn set_apps_key(&mut self, key: &AppsKey) -> Result<(), DriverError> {
+        self.driver.set_apps_key(key)
+    }
+
+    pub fn set_nwks_key(&mut self, key: &NwksKey) -> Result<(), DriverError> {
+        self.driver.set_nwks_key(key)
+    }
+
+    pub fn set_data_rate(&mut self, dr: u8) -> Result<(), DriverError> {
+        self.driver.set_data_rate(dr)
+    }
+
+    pub fn set_sub_band(&mut self, sub_band: u8) -> Result<(), DriverError> {
+        self.driver.set_sub_band(sub_band)
+    }
+
+    /// See `Rak811Driver::set_class`.
+    pub fn set_class(&mut self, class: DeviceClass) -> Result<(), DriverError> {
+        self.driver.set_class(class)
+    }
+
+    /// Join a LoRa Network using the specified mode.
+    pub fn join(
+        mut self,
+        mode: ConnectMode,
+    ) -> JoinTransition<W, R, RST, BUF, RXQ, CMD, DriverError> {
+        match self.driver.join(mode) {
+            Ok(_) => Ok(self.transition()),
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Join a LoRa Network using a `JoinManager`. While the join is in progress,
+    /// `nb::Error::WouldBlock` is returned together with the unjoined state.
+    pub fn poll_join(
+        mut self,
+        manager: &mut JoinManager,
+        now_ms: u64,
+    ) -> JoinTransition<W, R, RST, BUF, RXQ, CMD, nb::Error<DriverError>> {
+        match manager.poll(&mut self.driver, now_ms) {
+            Ok(_) => Ok(self.transition()),
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
+impl<W, R, RST, BUF, RXQ, CMD> Rak811<W, R, RST, LoraWan<Joined>, BUF, RXQ, CMD>
+where
+    W: Write<u8>,
+    R: Read<u8>,
+    RST: OutputPin,
+    BUF: ArrayLength<u8>,
+    RXQ: ArrayLength<Response>,
+    CMD: ArrayLength<u8>,
+{
+    /// See `Rak811Driver::send`.
+    pub fn send(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<UplinkResult, DriverError> {
+        self.driver.send(qos, port, data)
+    }
+
+    /// See `Rak811Driver::max_payload_len`.
+    pub fn max_payload_len(&mut self) -> Result<usize, DriverError> {
+        self.driver.max_payload_len()
+    }
+
+    /// See `Rak811Driver::time_on_air`.
+    pub fn time_on_air(&mut self, len: usize) -> Result<u32, DriverError> {
+        self.driver.time_on_air(len)
+    }
+
+    /// See `Rak811Driver::start_send`.
+    pub fn start_send(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<(), DriverError> {
+        self.driver.start_send(qos, port, data)
+    }
+
+    /// See `Rak811Driver::poll_send`.
+    pub fn poll_send(&mut self) -> nb::Result<UplinkResult, DriverError> {
+        self.driver.poll_send()
+    }
+
+    /// See `Rak811Driver::set_uplink_status`.
+    pub fn set_uplink_status(&mut self, enabled: bool) {
+        self.driver.set_uplink_status(enabled)
+    }
+
+    /// See `Rak811Driver::poll_downlinks`.
+    pub fn poll_downlinks(&mut self, port: Port, limit: usize) -> Result<usize, DriverError> {
+        self.driver.poll_downlinks(port, limit)
+    }
+
+    /// See `Rak811Driver::set_repeated_downlinks`.
+    pub fn set_repeated_downlinks(&mut self, policy: RepeatedDownlinks) {
+        self.driver.set_repeated_downlinks(policy)
+    }
+
+    /// See `Rak811Driver::next_downlink`.
+    pub fn next_downlink(&mut self) -> Result<Option<Downlink>, DriverError> {
+        self.driver.next_downlink()
+    }
+
+    /// See `Rak811Driver::try_recv`.
+    pub fn try_recv(&mut self, port: Port, rx_buf: &mut [u8]) -> Result<usize, DriverError> {
+        self.driver.try_recv(port, rx_buf)
+    }
+
+    /// See `Rak811Driver::try_recv_with`.
+    pub fn try_recv_with<T, F>(&mut self, port: Port, f: F) -> Result<Option<T>, DriverError>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        self.driver.try_recv_with(port, f)
+    }
+}
+
+// Driver tests log through defmt, which needs a global logger not available on the host.
+#[cfg(all(test, not(feature = "defmt")))]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockDriver, Module};
+
+    fn configure(module: &Module) -> Rak811<mock::Tx, mock::Rx, mock::Pin, LoraWan<NotJoined>> {
+        module
+            .expect("at+band=US915", "OK\r\n")
+            .expect("at+mode=0", "OK\r\n");
+        let driver: MockDriver = module.driver().unwrap();
+        Rak811::new(driver)
+            .into_lorawan(LoraRegion::US915)
+            .map_err(|(_, e)| e)
+            .unwrap()
+    }
+
+    #[test]
+    fn configure_join_and_send() {
+        let module = Module::new();
+        let node = configure(&module);
+        module.expect("at+join=otaa", "OK\r\nat+recv=3,0,0\r\n");
+        let mut node = node.join(ConnectMode::OTAA).map_err(|(_, e)| e).unwrap();
+        module
+            .expect("at+get_config=dr", "OK0\r\n")
+            .expect("at+send=0,1,12", "OK\r\nat+recv=2,0,0\r\n");
+        let result = node.send(QoS::Unconfirmed, 1, &[0x12]).unwrap();
+        module.done();
+        assert!(!result.acked);
+        assert!(node.into_inner().is_joined());
+    }
+
+    #[test]
+    fn failed_join_hands_back_the_node() {
+        let module = Module::new();
+        let node = configure(&module);
+        module.expect("at+join=otaa", "OK\r\nat+recv=4,0,0\r\n");
+        let node = match node.join(ConnectMode::OTAA) {
+            Err((node, DriverError::JoinFailed)) => node,
+            _ => panic!("join did not fail"),
+        };
+        module.expect("at+join=otaa", "OK\r\nat+recv=3,0,0\r\n");
+        assert!(node.join(ConnectMode::OTAA).is_ok());
+        module.done();
+    }
+}